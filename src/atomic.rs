@@ -0,0 +1,105 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use anyhow::Result;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// An output file written to a temporary file next to its target, and only renamed into place
+/// by [`AtomicFile::commit`]. Dropping it uncommitted removes the temporary file, so the target
+/// either keeps its old content or gets the complete new one.
+pub struct AtomicFile {
+    file: NamedTempFile,
+    target: PathBuf,
+}
+
+impl AtomicFile {
+    pub fn create(target: &Path) -> Result<Self> {
+        let dir = match target.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(".uqm-").suffix(".tmp");
+        // same mode as `File::create` rather than the private default of temporary files
+        #[cfg(unix)]
+        builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+        let file = builder.tempfile_in(dir)?;
+        Ok(Self {
+            file,
+            target: target.to_path_buf(),
+        })
+    }
+
+    /// Path of the temporary file.
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    pub fn as_file_mut(&mut self) -> &mut File {
+        self.file.as_file_mut()
+    }
+
+    /// Sync the content to disk and rename it over the target.
+    pub fn commit(self) -> Result<()> {
+        self.file.as_file().sync_all()?;
+        self.file.persist(&self.target)?;
+        sync_dir(&self.target);
+        Ok(())
+    }
+}
+
+/// Make the rename itself durable. Best effort: not every platform can open a directory.
+fn sync_dir(target: &Path) {
+    if let Some(dir) = target.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+}
+
+/// Copy `source` to `target` through an [`AtomicFile`], returning the number of bytes copied.
+pub fn copy(source: &Path, target: &Path) -> Result<u64> {
+    let mut output = AtomicFile::create(target)?;
+    let n = io::copy(&mut File::open(source)?, output.as_file_mut())?;
+    output.commit()?;
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_commit_and_abort() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("song.flac");
+        fs::write(&target, b"old").unwrap();
+
+        let mut output = AtomicFile::create(&target).unwrap();
+        output.as_file_mut().write_all(b"partial").unwrap();
+        drop(output);
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let mut output = AtomicFile::create(&target).unwrap();
+        output.as_file_mut().write_all(b"new").unwrap();
+        output.commit().unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,142 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::term::{self, Color};
+use crate::verify::{self, Verdict, Verification};
+use crate::{exit_code, walk, GlobalArgs};
+use anyhow::Result;
+use clap::Args;
+use serde::Serialize;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use umc_qmc::audio;
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    /// Walk subdirectories of the given directories
+    #[arg(short = 'R', long, default_value_t = false)]
+    recursive: bool,
+
+    /// Print one JSON object per file instead of one line
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    /// Decrypted files or directories to verify
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+/// Outcome of verifying a file.
+#[derive(Serialize)]
+struct Report {
+    path: PathBuf,
+    audio: Option<String>,
+    /// `None` for audio types which are not checked.
+    verification: Option<Verdict>,
+    verification_detail: Option<String>,
+    error: Option<String>,
+}
+
+impl VerifyArgs {
+    pub fn run(&self, global: &GlobalArgs) -> Result<i32> {
+        let (mut verified, mut suspicious, mut broken, mut failed) = (0, 0, 0, 0);
+        for path in &self.paths {
+            let files = match path.is_dir() {
+                true => walk::collect(path, None, self.recursive, global.verbose)?
+                    .into_iter()
+                    .map(|entry| entry.path)
+                    .collect(),
+                false => vec![path.clone()],
+            };
+            for file in files {
+                let mut report = Report {
+                    path: file.clone(),
+                    audio: None,
+                    verification: None,
+                    verification_detail: None,
+                    error: None,
+                };
+                match check(&file, &mut report) {
+                    Ok(Some(verification)) => match verification.verdict {
+                        Verdict::Verified => verified += 1,
+                        Verdict::Suspicious => suspicious += 1,
+                        Verdict::Broken => broken += 1,
+                    },
+                    Ok(None) => {}
+                    Err(err) => {
+                        report.error = Some(err.to_string());
+                        failed += 1;
+                    }
+                }
+                match self.json {
+                    true => println!("{}", serde_json::to_string(&report)?),
+                    false => print_report(&report, global),
+                }
+            }
+        }
+        term::info(format!(
+            "{} file(s) verified, {} suspicious, {} broken",
+            verified, suspicious, broken
+        ));
+        Ok(exit_code(verified + suspicious, broken + failed))
+    }
+}
+
+fn check(path: &Path, report: &mut Report) -> Result<Option<Verification>> {
+    let mut file = File::open(path)?;
+    let mut header = Vec::with_capacity(audio::SNIFF_LEN);
+    (&mut file)
+        .take(audio::SNIFF_LEN as u64)
+        .read_to_end(&mut header)?;
+    let Some(audio) = audio::detect(&header) else {
+        return Ok(None);
+    };
+    report.audio = Some(audio.to_string());
+    let verification = verify::verify(&mut file, audio)?;
+    if let Some(verification) = &verification {
+        report.verification = Some(verification.verdict);
+        report.verification_detail = verification.detail.clone();
+    }
+    Ok(verification)
+}
+
+fn print_report(report: &Report, global: &GlobalArgs) {
+    let path = report.path.display();
+    if let Some(error) = &report.error {
+        term::error(format!("{}: {}", path, error));
+        return;
+    }
+    let verdict = match (report.verification, &report.audio) {
+        (Some(verdict), _) => verdict,
+        (None, audio) if global.verbose => {
+            match audio {
+                Some(audio) => println!("{}: {} is not checked", path, audio),
+                None => println!("{}: unknown audio type", path),
+            }
+            return;
+        }
+        (None, _) => return,
+    };
+    let color = match verdict {
+        Verdict::Verified if global.quiet => return,
+        Verdict::Verified => Color::Green,
+        Verdict::Suspicious => Color::Yellow,
+        Verdict::Broken => Color::Red,
+    };
+    let verification = Verification {
+        verdict,
+        detail: report.verification_detail.clone(),
+    };
+    println!("{}: {}", path, term::paint(verification, color));
+}
@@ -0,0 +1,218 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::name::OnConflict;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Path of the configuration under the configuration directory.
+const CONFIG_PATH: &str = "uqm/config.toml";
+
+/// Defaults of the command line options, set at the top level of the configuration or in a
+/// profile.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub db: Option<Vec<PathBuf>>,
+    pub keys: Option<Vec<PathBuf>>,
+    pub output: Option<PathBuf>,
+    pub name_template: Option<String>,
+    pub jobs: Option<usize>,
+    pub replace: Option<bool>,
+    pub on_conflict: Option<OnConflict>,
+    /// Output extension by encrypted extension, e.g. `mgg = "opus"`.
+    #[serde(default)]
+    pub extensions: BTreeMap<String, String>,
+}
+
+/// The top-level settings, and the `[profiles.<name>]` tables.
+#[derive(Debug, Default)]
+struct Config {
+    defaults: Settings,
+    profiles: BTreeMap<String, Settings>,
+}
+
+impl Settings {
+    /// `self` with the values set in `other` replacing its own.
+    fn merge(mut self, other: Settings) -> Settings {
+        self.extensions.extend(other.extensions);
+        Settings {
+            db: other.db.or(self.db),
+            keys: other.keys.or(self.keys),
+            output: other.output.or(self.output),
+            name_template: other.name_template.or(self.name_template),
+            jobs: other.jobs.or(self.jobs),
+            replace: other.replace.or(self.replace),
+            on_conflict: other.on_conflict.or(self.on_conflict),
+            extensions: self.extensions,
+        }
+    }
+
+    /// Make the relative paths relative to `base` instead of the working directory.
+    fn resolve(&mut self, base: &Path) {
+        let paths = self.db.iter_mut().chain(self.keys.iter_mut()).flatten();
+        for path in paths.chain(self.output.as_mut()) {
+            if path.is_relative() {
+                *path = base.join(&*path);
+            }
+        }
+    }
+}
+
+/// Default location of the configuration: `$XDG_CONFIG_HOME/uqm/config.toml`, or
+/// `~/.config/uqm/config.toml` when `XDG_CONFIG_HOME` is not set.
+fn default_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join(CONFIG_PATH))
+}
+
+/// Read the settings of `profile`, or the top-level ones, from the configuration at `path`, or
+/// at the default location. A missing default configuration is empty.
+pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Settings> {
+    let (path, required) = match path {
+        Some(path) => (Some(path.to_path_buf()), true),
+        None => (default_path(), false),
+    };
+    let config = match path {
+        Some(path) if required || path.exists() => {
+            let text = fs::read_to_string(&path)
+                .with_context(|| format!("failed to read config {:?}", path))?;
+            let mut config = parse(&text).with_context(|| format!("invalid config {:?}", path))?;
+            let base = path.parent().unwrap_or(Path::new(""));
+            config.defaults.resolve(base);
+            config
+                .profiles
+                .values_mut()
+                .for_each(|settings| settings.resolve(base));
+            config
+        }
+        _ => Config::default(),
+    };
+    select(config, profile)
+}
+
+fn parse(text: &str) -> Result<Config> {
+    // parsed apart, so that unknown top-level keys are rejected like the ones of the profiles
+    let mut table = toml::from_str::<toml::Table>(text)?;
+    let profiles = match table.remove("profiles") {
+        Some(profiles) => profiles.try_into()?,
+        None => BTreeMap::new(),
+    };
+    let config = Config {
+        defaults: toml::Value::Table(table).try_into()?,
+        profiles,
+    };
+    let templates = config
+        .profiles
+        .values()
+        .chain([&config.defaults])
+        .filter_map(|settings| settings.name_template.as_deref());
+    for template in templates {
+        template
+            .parse::<crate::name::NameTemplate>()
+            .map_err(|err| anyhow!("name_template {:?}: {}", template, err))?;
+    }
+    Ok(config)
+}
+
+fn select(mut config: Config, profile: Option<&str>) -> Result<Settings> {
+    let Some(name) = profile else {
+        return Ok(config.defaults);
+    };
+    match config.profiles.remove(name) {
+        Some(settings) => Ok(config.defaults.merge(settings)),
+        None if config.profiles.is_empty() => bail!("unknown profile {:?}", name),
+        None => bail!(
+            "unknown profile {:?}, expected one of: {}",
+            name,
+            config.profiles.into_keys().collect::<Vec<_>>().join(", ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+db = ["player_process_db"]
+output = "/music"
+jobs = 4
+
+[extensions]
+mgg = "opus"
+mflac = "flac"
+
+[profiles.laptop]
+output = "/home/me/music"
+name_template = "{artist}/{title}"
+replace = true
+on_conflict = "skip-if-identical"
+
+[profiles.laptop.extensions]
+mgg = "ogg"
+"#;
+
+    #[test]
+    fn test_profiles() {
+        let defaults = select(parse(CONFIG).unwrap(), None).unwrap();
+        assert_eq!(defaults.db, Some(vec![PathBuf::from("player_process_db")]));
+        assert_eq!(defaults.output, Some(PathBuf::from("/music")));
+        assert_eq!(defaults.jobs, Some(4));
+        assert_eq!(defaults.replace, None);
+        assert_eq!(defaults.extensions["mgg"], "opus");
+
+        let laptop = select(parse(CONFIG).unwrap(), Some("laptop")).unwrap();
+        assert_eq!(laptop.db, defaults.db);
+        assert_eq!(laptop.output, Some(PathBuf::from("/home/me/music")));
+        assert_eq!(laptop.name_template.as_deref(), Some("{artist}/{title}"));
+        assert_eq!(laptop.jobs, Some(4));
+        assert_eq!(laptop.replace, Some(true));
+        assert_eq!(laptop.on_conflict, Some(OnConflict::SkipIfIdentical));
+        assert_eq!(laptop.extensions["mgg"], "ogg");
+        assert_eq!(laptop.extensions["mflac"], "flac");
+
+        let err = select(parse(CONFIG).unwrap(), Some("desktop")).unwrap_err();
+        assert!(err.to_string().contains("laptop"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(parse("dbs = []").is_err());
+        assert!(parse("[profiles.a]\nouput = \"x\"").is_err());
+        assert!(parse("jobs = \"4\"").is_err());
+        assert!(parse("on_conflict = \"ask\"").is_err());
+        assert!(parse("name_template = \"/{title}\"").is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, CONFIG).unwrap();
+        let settings = load(Some(&path), None).unwrap();
+        assert_eq!(
+            settings.db,
+            Some(vec![dir.path().join("player_process_db")])
+        );
+        assert_eq!(settings.output, Some(PathBuf::from("/music")));
+        assert!(load(Some(&dir.path().join("missing.toml")), None).is_err());
+    }
+}
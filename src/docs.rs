@@ -0,0 +1,62 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{term, EXIT_SUCCESS};
+use anyhow::Result;
+use clap::{Args, Command};
+use clap_complete::Shell;
+use clap_mangen::Man;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+#[derive(Args)]
+pub struct CompletionsArgs {
+    /// The shell to complete for
+    shell: Shell,
+}
+
+#[derive(Args)]
+pub struct ManArgs {
+    /// Write uqm.1 and a page per subcommand, e.g. uqm-decrypt.1, to this directory
+    /// instead of printing uqm.1
+    #[arg(short, long, value_name = "DIR")]
+    output: Option<PathBuf>,
+}
+
+impl CompletionsArgs {
+    /// Print the completion script of `command` for the shell.
+    pub fn run(&self, mut command: Command) -> Result<i32> {
+        let name = command.get_name().to_string();
+        // clap_complete panics on write errors, e.g. a closed pipe
+        let mut buffer = Vec::new();
+        clap_complete::generate(self.shell, &mut command, name, &mut buffer);
+        io::stdout().write_all(&buffer)?;
+        Ok(EXIT_SUCCESS)
+    }
+}
+
+impl ManArgs {
+    /// Render the man pages of `command`, built from its clap definitions.
+    pub fn run(&self, command: Command) -> Result<i32> {
+        match &self.output {
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                clap_mangen::generate_to(command, dir)?;
+                term::info(format!("man pages written to {:?}", dir));
+            }
+            None => Man::new(command).render(&mut io::stdout())?,
+        }
+        Ok(EXIT_SUCCESS)
+    }
+}
@@ -0,0 +1,309 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::job::{self, KeySource};
+use crate::keys::{KeyStore, Query};
+use crate::{exit_code, utils, walk, GlobalArgs, KeySourceArgs};
+use anyhow::Result;
+use clap::Args;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use umc_qmc::format::{self, CipherKind};
+use umc_qmc::{audio, QMCCipher, QmcReader};
+
+#[derive(Args)]
+pub struct InspectArgs {
+    #[command(flatten)]
+    pub sources: KeySourceArgs,
+
+    /// Walk subdirectories of the given directories
+    #[arg(short = 'R', long, default_value_t = false)]
+    recursive: bool,
+
+    /// Print one JSON object per file instead of the human-readable report
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    /// Files or directories to inspect
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct ScanArgs {
+    #[command(flatten)]
+    pub sources: KeySourceArgs,
+
+    /// Walk subdirectories
+    #[arg(short = 'R', long, default_value_t = false)]
+    recursive: bool,
+
+    /// Print the summary as a JSON object
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    /// The directory to scan
+    input: PathBuf,
+}
+
+/// What could be learned about a file without decrypting it.
+#[derive(Serialize, Default)]
+struct Report {
+    path: PathBuf,
+    /// Encrypted extension, `None` for files that are not QMC encrypted.
+    format: Option<&'static str>,
+    footer: Option<&'static str>,
+    footer_size: usize,
+    embedded_ekey: bool,
+    /// `None` when neither a database nor a key file is given.
+    database_key: Option<bool>,
+    key_length: Option<usize>,
+    cipher: Option<&'static str>,
+    audio: Option<String>,
+    error: Option<String>,
+}
+
+impl InspectArgs {
+    pub fn run(&self) -> Result<i32> {
+        let keys = match self.sources.is_empty() {
+            true => None,
+            false => Some(self.sources.load()?.0),
+        };
+        let (mut succeeded, mut failed) = (0, 0);
+        for path in &self.paths {
+            let files = match path.is_dir() {
+                true => walk::collect(path, None, self.recursive, false)?
+                    .into_iter()
+                    .map(|entry| entry.path)
+                    .collect(),
+                false => vec![path.clone()],
+            };
+            for file in files {
+                let mut report = Report {
+                    path: file.clone(),
+                    ..Default::default()
+                };
+                match inspect(&file, keys.as_ref(), &mut report) {
+                    Ok(()) => succeeded += 1,
+                    Err(err) => {
+                        report.error = Some(err.to_string());
+                        failed += 1;
+                    }
+                }
+                match self.json {
+                    true => println!("{}", serde_json::to_string(&report)?),
+                    false => print_report(&report),
+                }
+            }
+        }
+        Ok(exit_code(succeeded, failed))
+    }
+}
+
+/// Counts of a directory scanned by [`ScanArgs`].
+#[derive(Serialize, Default)]
+struct Scan {
+    files: usize,
+    encrypted: usize,
+    /// Encrypted files by extension.
+    formats: BTreeMap<&'static str, usize>,
+    /// Encrypted files embedding their key.
+    embedded_key: usize,
+    /// Encrypted files for which a working key was found.
+    decryptable: usize,
+    /// Encrypted files without any key.
+    no_key: usize,
+    /// Encrypted files whose keys do not work, or which could not be read.
+    failing: usize,
+    /// Files of the database which are not in the directory.
+    missing: usize,
+}
+
+impl ScanArgs {
+    pub fn run(&self, global: &GlobalArgs) -> Result<i32> {
+        let (keys, songs) = self.sources.load()?;
+        let keys = Some(keys).filter(|_| !self.sources.is_empty());
+        let entries = walk::collect(&self.input, None, self.recursive, global.verbose)?;
+        let mut scan = Scan::default();
+        let (mut no_key, mut failing) = (Vec::new(), Vec::new());
+        for entry in &entries {
+            let mut report = Report {
+                path: entry.path.clone(),
+                ..Default::default()
+            };
+            let result = inspect(&entry.path, keys.as_ref(), &mut report);
+            scan.files += 1;
+            let Some(format) = report.format else {
+                continue;
+            };
+            scan.encrypted += 1;
+            *scan.formats.entry(format).or_default() += 1;
+            if report.embedded_ekey {
+                scan.embedded_key += 1;
+            }
+            match (result, report.cipher) {
+                (Err(err), _) => failing.push((&entry.path, err.to_string())),
+                (Ok(()), Some(_)) => scan.decryptable += 1,
+                (Ok(()), None) => no_key.push(&entry.path),
+            }
+        }
+        scan.no_key = no_key.len();
+        scan.failing = failing.len();
+        let present = entries
+            .iter()
+            .filter_map(|entry| entry.path.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect();
+        let missing = songs.missing(&present);
+        scan.missing = missing.len();
+
+        match self.json {
+            true => println!("{}", serde_json::to_string(&scan)?),
+            false => print_scan(&scan),
+        }
+        if global.verbose {
+            for path in &no_key {
+                println!("no key: {}", path.display());
+            }
+            for (path, err) in &failing {
+                println!("failing: {}: {}", path.display(), err);
+            }
+            for path in &missing {
+                println!("missing: {}", path);
+            }
+        }
+        Ok(exit_code(scan.decryptable, scan.no_key + scan.failing))
+    }
+}
+
+fn inspect(path: &Path, database: Option<&KeyStore>, report: &mut Report) -> Result<()> {
+    let filename = utils::get_filename(path)?;
+    let Some(format) = format::from_filename(&filename) else {
+        return Ok(());
+    };
+    report.format = Some(format.extension);
+    let mut file = File::open(path)?;
+    let (size, metadata) = utils::read_footer(&mut file)?;
+    let metadata = match metadata {
+        // a QMCv1 file is not expected to have a footer
+        Err(_) if format.cipher == CipherKind::V1 => None,
+        metadata => metadata?,
+    };
+    let (cipher, len) = match format.cipher_for(metadata.as_ref()) {
+        CipherKind::V1 => {
+            report.cipher = Some("V1");
+            (QMCCipher::V1, size)
+        }
+        CipherKind::V2 => {
+            if let Some(metadata) = &metadata {
+                report.footer = Some(metadata.data.kind());
+                report.footer_size = metadata.size;
+                report.embedded_ekey = metadata.ekey.is_some();
+            }
+            let query = Query {
+                path,
+                filename: &filename,
+                media_mid: metadata.as_ref().and_then(|m| m.data.media_mid()),
+            };
+            let database_ekeys = database.map(|database| database.candidates(query));
+            report.database_key = database_ekeys.as_ref().map(|ekeys| !ekeys.is_empty());
+            let candidates = metadata
+                .as_ref()
+                .and_then(|metadata| metadata.ekey.clone())
+                .map(|ekey| (ekey, KeySource::Embedded))
+                .into_iter()
+                .chain(
+                    database_ekeys
+                        .into_iter()
+                        .flatten()
+                        .map(|ekey| (ekey.to_string(), KeySource::Database)),
+                )
+                .collect::<Vec<_>>();
+            if candidates.is_empty() {
+                return Ok(());
+            }
+            let len = size - report.footer_size as u64;
+            let selected = job::select_key(&mut file, len, &filename, candidates, true)?;
+            report.key_length = Some(selected.key.len());
+            let cipher = selected.cipher;
+            report.cipher = Some(cipher.kind());
+            (QMCCipher::V2(cipher), len)
+        }
+    };
+    let mut header = Vec::with_capacity(audio::SNIFF_LEN);
+    QmcReader::new(file, cipher, len)?
+        .take(audio::SNIFF_LEN as u64)
+        .read_to_end(&mut header)?;
+    report.audio = audio::detect(&header).map(|audio| audio.to_string());
+    Ok(())
+}
+
+fn print_report(report: &Report) {
+    fn or_none<T: ToString>(value: Option<T>) -> String {
+        value.map_or("-".into(), |value| value.to_string())
+    }
+    fn yes_no(value: bool) -> &'static str {
+        match value {
+            true => "yes",
+            false => "no",
+        }
+    }
+
+    println!("{}", report.path.display());
+    let Some(format) = report.format else {
+        println!("  not a QMC encrypted file");
+        return;
+    };
+    println!("  format:        {}", format);
+    match report.footer {
+        Some(footer) => println!("  footer:        {} ({} bytes)", footer, report.footer_size),
+        None => println!("  footer:        -"),
+    }
+    println!("  embedded ekey: {}", yes_no(report.embedded_ekey));
+    println!(
+        "  database key:  {}",
+        or_none(report.database_key.map(yes_no))
+    );
+    println!("  key length:    {}", or_none(report.key_length));
+    println!("  cipher:        {}", or_none(report.cipher));
+    println!("  audio:         {}", or_none(report.audio.as_ref()));
+    if let Some(error) = &report.error {
+        println!("  error:         {}", error);
+    }
+}
+
+fn print_scan(scan: &Scan) {
+    let formats = scan
+        .formats
+        .iter()
+        .map(|(format, count)| format!("{}: {}", format, count))
+        .collect::<Vec<_>>();
+    println!("{} file(s) scanned", scan.files);
+    match formats.is_empty() {
+        true => println!("  encrypted:     {}", scan.encrypted),
+        false => println!(
+            "  encrypted:     {} ({})",
+            scan.encrypted,
+            formats.join(", ")
+        ),
+    }
+    println!("  embedded key:  {}", scan.embedded_key);
+    println!("  decryptable:   {}", scan.decryptable);
+    println!("  no key:        {}", scan.no_key);
+    println!("  failing:       {}", scan.failing);
+    println!("  not encrypted: {}", scan.files - scan.encrypted);
+    println!("  missing:       {} file(s) of the database", scan.missing);
+}
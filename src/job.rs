@@ -0,0 +1,1027 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::atomic::{self, AtomicFile};
+use crate::keys::{KeyStore, Query};
+use crate::name::{self, Claims, NameTemplate, OnConflict, Resolution};
+use crate::pool::{Budget, Log};
+use crate::report::{Record, Status};
+use crate::song::{Song, SongIndex};
+use crate::verify::{self, Verdict};
+use crate::walk::Entry;
+use crate::{tag, utils};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::PathBuf;
+use thiserror::Error;
+use umc_qmc::audio::{self, AudioType};
+use umc_qmc::footer::Metadata;
+use umc_qmc::format::{self, CipherKind, Format};
+use umc_qmc::{QMCCipher, QMCv2Cipher, QmcReader};
+
+pub const BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum JobError {
+    #[error("could not find ekey for {0}")]
+    KeyNotFound(String),
+    #[error("invalid ekey: {0}")]
+    InvalidEKey(anyhow::Error),
+    #[error("incomplete output: wrote {written} of {expected} bytes")]
+    Truncated { written: u64, expected: u64 },
+    #[error("none of the {0} candidate key(s) decrypts {1} to a known audio stream")]
+    KeyMismatch(usize, String),
+    #[error("the output {0:?} already exists")]
+    OutputExists(PathBuf),
+    #[error("the output {0:?} is the input itself")]
+    OutputIsInput(PathBuf),
+}
+
+impl JobError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            JobError::KeyNotFound(_) => "missing_key",
+            JobError::InvalidEKey(_) => "invalid_ekey",
+            JobError::Truncated { .. } => "truncated",
+            JobError::KeyMismatch(..) => "key_mismatch",
+            JobError::OutputExists(_) => "output_exists",
+            JobError::OutputIsInput(_) => "output_is_input",
+        }
+    }
+}
+
+/// A QMCv2 key picked by [`select_key`].
+pub struct SelectedKey {
+    pub cipher: QMCv2Cipher,
+    pub source: KeySource,
+    pub key: Vec<u8>,
+    /// Candidates tried before, which did not decrypt to audio.
+    pub rejected: usize,
+}
+
+/// Pick the first of `candidates` which decrypts the beginning of the `len` bytes of audio in
+/// `file` to a known audio stream, see [`audio::detect`]. Without `check`, the first candidate
+/// is used as is.
+pub fn select_key(
+    file: &mut File,
+    len: u64,
+    filename: &str,
+    candidates: Vec<(String, KeySource)>,
+    check: bool,
+) -> Result<SelectedKey> {
+    let count = candidates.len();
+    let mut invalid = None;
+    let mut rejected = 0;
+    for (ekey, source) in candidates {
+        let key = match umc_qmc::ekey::decrypt(ekey) {
+            Ok(key) => key,
+            Err(err) if check => {
+                invalid = Some(err);
+                continue;
+            }
+            Err(err) => Err(JobError::InvalidEKey(err))?,
+        };
+        let cipher = QMCv2Cipher::new(&key)?;
+        if check {
+            let mut header = Vec::with_capacity(audio::SNIFF_LEN);
+            QmcReader::new(&mut *file, QMCCipher::V2(cipher.clone()), len)?
+                .take(audio::SNIFF_LEN as u64)
+                .read_to_end(&mut header)?;
+            if audio::detect(&header).is_none() {
+                rejected += 1;
+                continue;
+            }
+        }
+        return Ok(SelectedKey {
+            cipher,
+            source,
+            key,
+            rejected,
+        });
+    }
+    match (invalid, rejected) {
+        (Some(err), 0) => Err(JobError::InvalidEKey(err))?,
+        _ => Err(JobError::KeyMismatch(count, filename.to_string()))?,
+    }
+}
+
+/// Settings shared by every file of a run.
+pub struct Context {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub keys: KeyStore,
+    pub budget: Budget,
+    pub replace: bool,
+    pub keep_ext: bool,
+    pub verbose: bool,
+    pub name_template: Option<NameTemplate>,
+    pub claims: Claims,
+    pub songs: SongIndex,
+    pub tag: bool,
+    /// Trial-decrypt the candidate keys, see [`select_key`].
+    pub check_key: bool,
+    pub verify: bool,
+    /// Key given on the command line for a single input, used instead of any other key.
+    pub ekey: Option<String>,
+    /// Output extension by lowercase encrypted extension, used instead of the detected one.
+    pub extensions: HashMap<String, String>,
+    pub on_conflict: OnConflict,
+}
+
+/// Where the key of an encrypted file comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySource {
+    /// QMCv1 files are encrypted with a fixed key.
+    Builtin,
+    Embedded,
+    Database,
+    /// Given with `--ekey`.
+    Argument,
+}
+
+/// What [`Context::process`] would do with a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Decrypt(KeySource),
+    Copy,
+    Skip(&'static str),
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Decrypt(KeySource::Builtin) => write!(f, "decrypt with the built-in key"),
+            Action::Decrypt(KeySource::Embedded) => write!(f, "decrypt with the embedded key"),
+            Action::Decrypt(KeySource::Database) => write!(f, "decrypt with the database key"),
+            Action::Decrypt(KeySource::Argument) => write!(f, "decrypt with the given key"),
+            Action::Copy => write!(f, "copy"),
+            Action::Skip(reason) => write!(f, "skip ({})", reason),
+        }
+    }
+}
+
+/// Planned action and output of a file, see [`Context::plan`].
+#[derive(Debug)]
+pub struct Plan {
+    pub action: Action,
+    pub output: Option<PathBuf>,
+    /// The output was renamed because another input claimed the same path.
+    pub renamed: bool,
+    /// What would be done about an output which already exists.
+    pub conflict: Option<Resolution>,
+}
+
+/// Ids of an encrypted file, see [`Context::identify`].
+struct Identity<'a> {
+    mid: Option<String>,
+    media_mid: Option<String>,
+    resource_id: Option<u64>,
+    song: Option<&'a Song>,
+}
+
+/// An encrypted file opened by [`Context::open`].
+struct Opened {
+    reader: QmcReader<File>,
+    source: KeySource,
+    /// The selected QMCv2 key, `None` for QMCv1 files.
+    key: Option<Vec<u8>>,
+    metadata: Option<Metadata>,
+}
+
+/// What [`Context::probe`] found out about a file: its action and output, and for an encrypted
+/// file, all that [`Context::process`] needs to decrypt it without looking up its key again.
+pub struct Probe<'a> {
+    pub action: Action,
+    /// Output of the file, `None` when it is skipped. Not claimed by the probe.
+    pub output: Option<PathBuf>,
+    unlocked: Option<Unlocked<'a>>,
+    /// Messages about the footer, key and audio type, printed when the file is processed.
+    log: Log,
+}
+
+/// An encrypted file whose key was selected, see [`Probe`].
+struct Unlocked<'a> {
+    key: Option<Vec<u8>>,
+    /// Length of the audio payload, without the footer.
+    len: u64,
+    footer: Option<&'static str>,
+    audio: Option<AudioType>,
+    identity: Identity<'a>,
+}
+
+impl Context {
+    // noinspection SpellCheckingInspection
+    /// Decrypt or copy `task` as found out by `probe`, whose output was claimed ahead by
+    /// [`Context::claim`]. When `probe` is `None`, e.g. because probing failed, the file is
+    /// probed and its output claimed here.
+    pub fn process(
+        &self,
+        task: &Entry,
+        probe: Option<&Probe>,
+        log: &mut Log,
+        record: &mut Record,
+    ) -> Result<()> {
+        if self.verbose {
+            log.out(format!("processing {:?}", task.path));
+        }
+        let filename = utils::get_filename(&task.path)?;
+        let probed;
+        let probe = match probe {
+            Some(probe) => {
+                log.extend(&probe.log);
+                probe
+            }
+            None => {
+                let mut probe = self.examine(task, log)?;
+                probe.output = probe.output.map(|output| self.claim(output, log).0);
+                probed = probe;
+                &probed
+            }
+        };
+        let Some(target) = probe.output.clone() else {
+            return Ok(());
+        };
+        let Some(unlocked) = &probe.unlocked else {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let (target, conflict) = self.resolve(task, target)?;
+            record.output = Some(target.clone());
+            record.conflict = conflict;
+            let identical = match conflict {
+                Some(Resolution::Overwritten)
+                    if self.on_conflict == OnConflict::SkipIfIdentical =>
+                {
+                    utils::same_content(&task.path, &target)?
+                }
+                _ => false,
+            };
+            if identical {
+                record.conflict = Some(Resolution::Identical);
+            } else if conflict != Some(Resolution::Skipped) {
+                record.bytes_written = atomic::copy(&task.path, &target)?;
+                record.status = Status::Copied;
+            }
+            self.log_conflict(&filename, record, log);
+            return Ok(());
+        };
+        record.footer = unlocked.footer;
+        let (audio, identity) = (unlocked.audio, &unlocked.identity);
+        let (target, conflict) = self.resolve(task, target)?;
+        record.output = Some(target.clone());
+        record.conflict = conflict;
+        if conflict == Some(Resolution::Skipped) {
+            self.log_conflict(&filename, record, log);
+            return Ok(());
+        }
+        let cipher = match &unlocked.key {
+            Some(key) => QMCCipher::V2(QMCv2Cipher::new(key)?),
+            None => QMCCipher::V1,
+        };
+        let mut reader = QmcReader::new(File::open(&task.path)?, cipher, unlocked.len)?;
+        let payload_size = reader.len();
+        let permit = self.budget.acquire(BUFFER_SIZE.min(payload_size as usize));
+        let mut buffer = vec![0u8; permit.size()];
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut output = AtomicFile::create(&target)?;
+        let mut writer = BufWriter::new(output.as_file_mut());
+        loop {
+            let n = reader.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&buffer[..n])?;
+            record.bytes_written += n as u64;
+        }
+        writer.flush()?;
+        drop(writer);
+        drop(buffer);
+        drop(permit);
+        if record.bytes_written != payload_size {
+            Err(JobError::Truncated {
+                written: record.bytes_written,
+                expected: payload_size,
+            })?;
+        }
+        if self.verify {
+            self.verify(output.as_file_mut(), audio, &filename, log, record)?;
+        }
+        if self.tag {
+            record.tagged = self.tag(output.as_file_mut(), audio, identity, &filename, log)?;
+        }
+        if conflict == Some(Resolution::Overwritten)
+            && self.on_conflict == OnConflict::SkipIfIdentical
+            && utils::same_content(output.path(), &target)?
+        {
+            // the temporary output is dropped, and the existing one kept
+            record.conflict = Some(Resolution::Identical);
+            record.bytes_written = 0;
+            self.log_conflict(&filename, record, log);
+            return Ok(());
+        }
+        output.commit()?;
+        self.log_conflict(&filename, record, log);
+        // the source is only removed once the complete output is in place, and not broken
+        if self.replace && matches!(record.verification, None | Some(Verdict::Verified)) {
+            fs::remove_file(&task.path)?;
+        }
+        record.status = Status::Decrypted;
+        if self.verbose {
+            log.out(format!("{} decrypted", filename))
+        }
+        Ok(())
+    }
+
+    /// Decrypt `task` into `writer` instead of an output file, returning the number of bytes
+    /// written.
+    pub fn stream<W: Write>(&self, task: &Entry, writer: &mut W, log: &mut Log) -> Result<u64> {
+        let filename = utils::get_filename(&task.path)?;
+        let Some(format) = format::from_filename(&filename) else {
+            bail!("{} is not an encrypted file", filename);
+        };
+        let mut record = Record::new(&task.path);
+        let Opened { mut reader, .. } = self.open(task, &filename, format, log, &mut record)?;
+        let expected = reader.len();
+        let written = io::copy(&mut reader, writer)?;
+        writer.flush()?;
+        if written != expected {
+            Err(JobError::Truncated { written, expected })?;
+        }
+        Ok(written)
+    }
+
+    /// Work out what [`Context::process`] would do without writing anything.
+    ///
+    /// The footer is parsed, the key looked up and the beginning of the audio decrypted to name
+    /// the output, so a file which would fail to decrypt fails here as well. Outputs are claimed
+    /// as they are planned, so tasks must be planned in order.
+    pub fn plan(&self, task: &Entry, log: &mut Log) -> Result<Plan> {
+        let probe = self.examine(task, log)?;
+        match probe.output {
+            Some(output) => {
+                let (output, renamed) = self.claim(output, log);
+                self.plan_output(task, probe.action, output, renamed)
+            }
+            None => Ok(Plan {
+                action: probe.action,
+                output: None,
+                renamed: false,
+                conflict: None,
+            }),
+        }
+    }
+
+    /// Find out what to do with `task` and name its output, without claiming it: the footer is
+    /// parsed, the key selected and the beginning of the audio decrypted. Messages are kept in
+    /// the probe, and printed by [`Context::process`].
+    pub fn probe(&self, task: &Entry) -> Result<Probe<'_>> {
+        let mut log = Log::default();
+        let probe = self.examine(task, &mut log)?;
+        Ok(Probe { log, ..probe })
+    }
+
+    /// [`Context::probe`], logging to `log` instead.
+    fn examine(&self, task: &Entry, log: &mut Log) -> Result<Probe<'_>> {
+        let filename = utils::get_filename(&task.path)?;
+        let Some(format) = format::from_filename(&filename) else {
+            let (action, output) = match self.output != self.input {
+                true => (Action::Copy, Some(self.target_dir(task).join(&filename))),
+                false => (Action::Skip("not an encrypted file"), None),
+            };
+            return Ok(Probe {
+                action,
+                output,
+                unlocked: None,
+                log: Log::default(),
+            });
+        };
+        let mut record = Record::new(&task.path);
+        let opened = self.open(task, &filename, format, log, &mut record)?;
+        let len = opened.reader.len();
+        let mut header = Vec::with_capacity(audio::SNIFF_LEN);
+        opened
+            .reader
+            .take(audio::SNIFF_LEN as u64)
+            .read_to_end(&mut header)?;
+        let (audio, audio_extension) = self.detect(format, &header, &filename, log);
+        let identity = self.identify(task, &filename, opened.metadata.as_ref());
+        let output = self.output_path(task, &filename, format, audio_extension, &identity, log);
+        Ok(Probe {
+            action: Action::Decrypt(opened.source),
+            output: Some(output),
+            unlocked: Some(Unlocked {
+                key: opened.key,
+                len,
+                footer: record.footer,
+                audio,
+                identity,
+            }),
+            log: Log::default(),
+        })
+    }
+
+    /// Plan of `action`, skipped or failed by the `--on-conflict` policy when `output` exists.
+    fn plan_output(
+        &self,
+        task: &Entry,
+        action: Action,
+        output: PathBuf,
+        renamed: bool,
+    ) -> Result<Plan> {
+        let (output, conflict) = self.resolve(task, output)?;
+        let action = match conflict {
+            Some(Resolution::Skipped) => Action::Skip("the output exists"),
+            _ => action,
+        };
+        Ok(Plan {
+            action,
+            output: Some(output),
+            renamed,
+            conflict,
+        })
+    }
+
+    /// Output directory of `task`, mirroring its place under the input directory.
+    fn target_dir(&self, task: &Entry) -> PathBuf {
+        match task.relative.parent() {
+            Some(parent) => self.output.join(parent),
+            None => self.output.clone(),
+        }
+    }
+
+    /// Open the encrypted `task`, exposing only its audio payload.
+    fn open(
+        &self,
+        task: &Entry,
+        filename: &str,
+        format: &Format,
+        log: &mut Log,
+        record: &mut Record,
+    ) -> Result<Opened> {
+        let mut file = File::open(&task.path)?;
+        let (size, metadata) = self.read_footer(&mut file, filename, format, log, record)?;
+        let (metadata, cipher, source, key) = match format.cipher_for(metadata.as_ref()) {
+            CipherKind::V1 => (None, QMCCipher::V1, KeySource::Builtin, None),
+            CipherKind::V2 => {
+                let footer_size = metadata.as_ref().map_or(0, |metadata| metadata.size as u64);
+                let query = Query {
+                    path: &task.path,
+                    filename,
+                    media_mid: metadata.as_ref().and_then(|m| m.data.media_mid()),
+                };
+                let mut candidates = Vec::new();
+                if let Some(ekey) = &self.ekey {
+                    candidates.push((ekey.clone(), KeySource::Argument));
+                } else {
+                    if let Some(ekey) = metadata.as_ref().and_then(|m| m.ekey.clone()) {
+                        candidates.push((ekey, KeySource::Embedded));
+                    }
+                    if self.check_key {
+                        candidates.extend(
+                            self.keys
+                                .candidates(query)
+                                .into_iter()
+                                .map(|ekey| (ekey.to_string(), KeySource::Database)),
+                        );
+                    } else if candidates.is_empty() {
+                        if let Some(ekey) = self.keys.get(query)? {
+                            candidates.push((ekey.to_string(), KeySource::Database));
+                        }
+                    }
+                }
+                if candidates.is_empty() {
+                    Err(JobError::KeyNotFound(filename.to_string()))?
+                }
+                let len = size.saturating_sub(footer_size);
+                let selected = select_key(&mut file, len, filename, candidates, self.check_key)?;
+                if selected.rejected > 0 {
+                    log.warn(format!(
+                        "{}: skipped {} key(s) which do not decrypt to a known audio stream",
+                        filename, selected.rejected
+                    ));
+                }
+                let cipher = QMCCipher::V2(selected.cipher);
+                (metadata, cipher, selected.source, Some(selected.key))
+            }
+        };
+        let footer_size = metadata.as_ref().map_or(0, |metadata| metadata.size as u64);
+        let reader = QmcReader::new(file, cipher, size.saturating_sub(footer_size))?;
+        Ok(Opened {
+            reader,
+            source,
+            key,
+            metadata,
+        })
+    }
+
+    /// Output of `task`, named by the template when one is given.
+    fn output_path(
+        &self,
+        task: &Entry,
+        filename: &str,
+        format: &Format,
+        audio_extension: &str,
+        identity: &Identity,
+        log: &mut Log,
+    ) -> PathBuf {
+        let default = format.output_filename(filename, audio_extension);
+        let name = match &self.name_template {
+            None => PathBuf::from(&default),
+            Some(template) => {
+                let song = identity.song;
+                let fields = name::Fields {
+                    stem: default
+                        .strip_suffix(&format!(".{}", audio_extension))
+                        .unwrap_or(&default)
+                        .to_string(),
+                    ext: audio_extension.to_string(),
+                    mid: identity.mid.clone(),
+                    media_mid: identity.media_mid.clone(),
+                    resource_id: identity.resource_id.map(|id| id.to_string()),
+                    title: song.and_then(|song| song.title.clone()),
+                    artist: song.and_then(|song| song.artist.clone()),
+                    album: song.and_then(|song| song.album.clone()),
+                };
+                template.render(&fields).unwrap_or_else(|| {
+                    log.warn(format!(
+                        "{}: some placeholders of the name template have no value, using {}",
+                        filename, default
+                    ));
+                    PathBuf::from(&default)
+                })
+            }
+        };
+        self.target_dir(task).join(name)
+    }
+
+    /// Apply the `--on-conflict` policy to `target` when it already exists, returning the path
+    /// to write, or the existing one when it is skipped, and what was done about it.
+    ///
+    /// With [`OnConflict::SkipIfIdentical`], the existing file is reported as overwritten: the
+    /// caller compares it with the new output, see [`utils::same_content`].
+    ///
+    /// A `target` which is the input of `task` fails whatever the policy, as overwriting it, or
+    /// removing it with `--replace`, would lose the input.
+    fn resolve(&self, task: &Entry, target: PathBuf) -> Result<(PathBuf, Option<Resolution>)> {
+        if fs::symlink_metadata(&target).is_err() {
+            return Ok((target, None));
+        }
+        if fs::canonicalize(&target)? == fs::canonicalize(&task.path)? {
+            return Err(JobError::OutputIsInput(target).into());
+        }
+        Ok(match self.on_conflict {
+            OnConflict::Skip => (target, Some(Resolution::Skipped)),
+            OnConflict::Overwrite | OnConflict::SkipIfIdentical => {
+                (target, Some(Resolution::Overwritten))
+            }
+            OnConflict::Rename => (self.claims.claim_free(&target), Some(Resolution::Renamed)),
+            OnConflict::Fail => Err(JobError::OutputExists(target))?,
+        })
+    }
+
+    fn log_conflict(&self, filename: &str, record: &Record, log: &mut Log) {
+        let (Some(resolution), Some(output), true) =
+            (record.conflict, &record.output, self.verbose)
+        else {
+            return;
+        };
+        match resolution {
+            Resolution::Renamed => log.out(format!(
+                "{}: the output exists, renamed to {:?}",
+                filename, output
+            )),
+            _ => log.out(format!(
+                "{}: the output {:?} exists, {}",
+                filename, output, resolution
+            )),
+        }
+    }
+
+    /// Claim `target` for a task, see [`Claims::claim`]. Also returns whether it was renamed
+    /// because another task claimed it first.
+    pub fn claim(&self, target: PathBuf, log: &mut Log) -> (PathBuf, bool) {
+        let (path, renamed) = self.claims.claim(target.clone());
+        if renamed {
+            log.warn(format!(
+                "{:?} is the output of another input, using {:?}",
+                target, path
+            ));
+        }
+        (path, renamed)
+    }
+
+    /// Sniff the audio type from the decrypted `header`, returning it with the extension of the
+    /// output, which is taken from the extension table instead with `keep_ext`.
+    fn detect(
+        &self,
+        format: &Format,
+        header: &[u8],
+        filename: &str,
+        log: &mut Log,
+    ) -> (Option<AudioType>, &str) {
+        let detected = audio::detect(&header[..header.len().min(audio::SNIFF_LEN)]);
+        if let Some(extension) = self.extensions.get(format.extension) {
+            return (detected, extension);
+        }
+        if self.keep_ext {
+            return (detected, format.audio_extension);
+        }
+        match detected {
+            Some(audio) => {
+                if self.verbose {
+                    log.out(format!("{}: detected {}", filename, audio));
+                }
+                (detected, audio.extension())
+            }
+            None => {
+                log.warn(format!("{}: unknown audio type", filename));
+                (None, format.audio_extension)
+            }
+        }
+    }
+
+    /// Ids found in the footer, and the song of the database they resolve to.
+    fn identify<'a>(
+        &'a self,
+        task: &Entry,
+        filename: &str,
+        metadata: Option<&Metadata>,
+    ) -> Identity<'a> {
+        let data = metadata.map(|metadata| &metadata.data);
+        let mid = data.and_then(|data| data.mid());
+        let media_mid = data.and_then(|data| data.media_mid());
+        Identity {
+            mid: mid.map(Into::into),
+            media_mid: media_mid.map(Into::into),
+            resource_id: data.and_then(|data| data.resource_id()),
+            song: self.songs.get(&task.path, filename, media_mid, mid),
+        }
+    }
+
+    /// Check the container of the decrypted `output` and mark `record` with the verdict, see
+    /// [`verify::verify`].
+    fn verify(
+        &self,
+        output: &mut File,
+        audio: Option<AudioType>,
+        filename: &str,
+        log: &mut Log,
+        record: &mut Record,
+    ) -> Result<()> {
+        let verification = match audio {
+            Some(audio) => verify::verify(output, audio)?,
+            None => None,
+        };
+        let Some(verification) = verification else {
+            if self.verbose {
+                log.out(format!("{}: audio type not verified", filename));
+            }
+            return Ok(());
+        };
+        match verification.verdict {
+            Verdict::Verified if !self.verbose => {}
+            Verdict::Verified => log.out(format!("{}: {}", filename, verification)),
+            _ => log.err(format!("{}: {}", filename, verification)),
+        }
+        record.verification = Some(verification.verdict);
+        record.verification_detail = verification.detail;
+        Ok(())
+    }
+
+    /// Add the known metadata to the decrypted `output`, see [`tag::write`]. Returns whether it
+    /// was tagged; an error means that `output` must not be kept.
+    fn tag(
+        &self,
+        output: &mut File,
+        audio: Option<AudioType>,
+        identity: &Identity,
+        filename: &str,
+        log: &mut Log,
+    ) -> Result<bool> {
+        let Some(audio) = audio else {
+            log.warn(format!("{}: unknown audio type, not tagged", filename));
+            return Ok(false);
+        };
+        let song = identity.song;
+        let tags = tag::Tags {
+            title: song.and_then(|song| song.title.clone()),
+            artist: song.and_then(|song| song.artist.clone()),
+            album: song.and_then(|song| song.album.clone()),
+            mid: identity.mid.clone(),
+            media_mid: identity.media_mid.clone(),
+        };
+        if tags.is_empty() {
+            if self.verbose {
+                log.out(format!("{}: no metadata to tag", filename));
+            }
+            return Ok(false);
+        }
+        match tag::write(output, audio, &tags)? {
+            None => {
+                if self.verbose {
+                    log.out(format!("{}: tagged", filename));
+                }
+                Ok(true)
+            }
+            Some(tag::Skipped::Unsupported) => {
+                log.warn(format!("{}: tagging {} is not supported", filename, audio));
+                Ok(false)
+            }
+            Some(tag::Skipped::Unreadable(err)) => {
+                log.warn(format!("{}: not tagged, {}", filename, err));
+                Ok(false)
+            }
+        }
+    }
+
+    /// Read the footer of `file`. A QMCv1 file is not expected to have one, so failing to find
+    /// or parse it is only reported for QMCv2 formats.
+    fn read_footer(
+        &self,
+        file: &mut File,
+        filename: &str,
+        format: &Format,
+        log: &mut Log,
+        record: &mut Record,
+    ) -> Result<(u64, Option<Metadata>)> {
+        let (size, metadata) = utils::read_footer(file)?;
+        let metadata = match metadata {
+            Ok(None) | Err(_) if format.cipher == CipherKind::V1 => None,
+            Ok(Some(metadata)) => {
+                if self.verbose {
+                    log.out(format!("{}: {:?}", filename, metadata));
+                }
+                record.footer = Some(metadata.data.kind());
+                Some(metadata)
+            }
+            Ok(None) => {
+                log.warn(format!("{}: could not find any qmc metadata", filename));
+                None
+            }
+            Err(err) => {
+                log.warn(format!(
+                    "{}: failed to parse qmc metadata: {}",
+                    filename, err
+                ));
+                None
+            }
+        };
+        Ok((size, metadata))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use umc_qmc::ekey::{self, EKeyVersion};
+
+    /// An encrypted FLAC header and the ekey it was encrypted with.
+    fn encrypted(dir: &Path) -> (PathBuf, String) {
+        let mut data = b"fLaC\0\0\0\x22".to_vec();
+        data.resize(audio::SNIFF_LEN, 0);
+        encrypt(&dir.join("a.mflac"), data)
+    }
+
+    /// Write `data` encrypted with a QMCv2 key to `path`, returning it with the ekey.
+    fn encrypt(path: &Path, mut data: Vec<u8>) -> (PathBuf, String) {
+        let key = (0..128).map(|i| (i * 7 + 3) as u8).collect::<Vec<_>>();
+        QMCv2Cipher::new(&key).unwrap().encrypt(&mut data, 0);
+        fs::write(path, &data).unwrap();
+        (
+            path.to_path_buf(),
+            ekey::encrypt(key, EKeyVersion::V2).unwrap(),
+        )
+    }
+
+    /// Settings of a run from `input` to `output`, with the defaults of the command line.
+    fn context(input: &Path, output: &Path) -> Context {
+        Context {
+            input: input.to_path_buf(),
+            output: output.to_path_buf(),
+            keys: KeyStore::default(),
+            budget: Budget::new(BUFFER_SIZE),
+            replace: false,
+            keep_ext: false,
+            verbose: false,
+            name_template: None,
+            claims: Claims::default(),
+            songs: SongIndex::default(),
+            tag: false,
+            check_key: true,
+            verify: false,
+            ekey: None,
+            extensions: HashMap::new(),
+            on_conflict: OnConflict::default(),
+        }
+    }
+
+    #[test]
+    fn test_select_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, good) = encrypted(dir.path());
+        let other = ekey::encrypt([9u8; 128], EKeyVersion::V1).unwrap();
+        let mut file = File::open(&path).unwrap();
+        let len = audio::SNIFF_LEN as u64;
+        let candidates = vec![
+            ("invalid".to_string(), KeySource::Embedded),
+            (other.clone(), KeySource::Database),
+            (good, KeySource::Database),
+        ];
+        let selected = select_key(&mut file, len, "a.mflac", candidates, true).unwrap();
+        assert_eq!(selected.source, KeySource::Database);
+        assert_eq!(selected.rejected, 1);
+
+        let candidates = vec![(other.clone(), KeySource::Database)];
+        let err = select_key(&mut file, len, "a.mflac", candidates, true)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<JobError>(),
+            Some(JobError::KeyMismatch(1, _))
+        ));
+        // unchecked, the first key is trusted
+        let candidates = vec![(other, KeySource::Database)];
+        assert!(select_key(&mut file, len, "a.mflac", candidates, false).is_ok());
+    }
+
+    #[test]
+    fn test_on_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, ekey) = encrypted(dir.path());
+        let output = dir.path().join("out");
+        fs::create_dir(&output).unwrap();
+        let target = output.join("a.flac");
+        fs::write(&target, b"old").unwrap();
+        let task = Entry {
+            path,
+            relative: PathBuf::from("a.mflac"),
+        };
+        let run = |on_conflict| {
+            let context = Context {
+                ekey: Some(ekey.clone()),
+                on_conflict,
+                ..context(dir.path(), &output)
+            };
+            let mut record = Record::new(&task.path);
+            let result = context.process(&task, None, &mut Log::default(), &mut record);
+            (result, record)
+        };
+
+        let (result, record) = run(OnConflict::Skip);
+        assert!(result.is_ok());
+        assert_eq!(record.status, Status::Skipped);
+        assert_eq!(record.conflict, Some(Resolution::Skipped));
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+
+        let (result, _) = run(OnConflict::Fail);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<JobError>(),
+            Some(JobError::OutputExists(_))
+        ));
+
+        let (_, record) = run(OnConflict::Rename);
+        assert_eq!(record.conflict, Some(Resolution::Renamed));
+        assert_eq!(record.output, Some(output.join("a (2).flac")));
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+
+        let (_, record) = run(OnConflict::SkipIfIdentical);
+        assert_eq!(record.status, Status::Decrypted);
+        assert_eq!(record.conflict, Some(Resolution::Overwritten));
+        assert!(fs::read(&target).unwrap().starts_with(b"fLaC"));
+
+        let (_, record) = run(OnConflict::SkipIfIdentical);
+        assert_eq!(record.status, Status::Skipped);
+        assert_eq!(record.conflict, Some(Resolution::Identical));
+    }
+
+    #[test]
+    fn test_replace_keeps_suspicious_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        // an MP3 frame header announcing 417 bytes, cut after 200
+        let mut data = b"\xFF\xFB\x90\x64".to_vec();
+        data.resize(200, 0);
+        let (path, ekey) = encrypt(&dir.path().join("a.mflac"), data);
+        let task = Entry {
+            path: path.clone(),
+            relative: PathBuf::from("a.mflac"),
+        };
+        let context = Context {
+            ekey: Some(ekey),
+            replace: true,
+            verify: true,
+            ..context(dir.path(), &output)
+        };
+        let mut record = Record::new(&task.path);
+        context
+            .process(&task, None, &mut Log::default(), &mut record)
+            .unwrap();
+        assert_eq!(record.verification, Some(Verdict::Suspicious));
+        assert!(output.join("a.mp3").exists());
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_output_is_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, ekey) = encrypted(dir.path());
+        let before = fs::read(&path).unwrap();
+        let task = Entry {
+            path: path.clone(),
+            relative: PathBuf::from("a.mflac"),
+        };
+        let context = Context {
+            ekey: Some(ekey),
+            replace: true,
+            extensions: HashMap::from([("mflac".into(), "mflac".into())]),
+            ..context(dir.path(), dir.path())
+        };
+        let mut record = Record::new(&task.path);
+        let result = context.process(&task, None, &mut Log::default(), &mut record);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<JobError>(),
+            Some(JobError::OutputIsInput(_))
+        ));
+        assert_eq!(fs::read(&path).unwrap(), before);
+    }
+
+    #[test]
+    fn test_probe_is_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, ekey) = encrypted(dir.path());
+        let output = dir.path().join("out");
+        let task = Entry {
+            path,
+            relative: PathBuf::from("a.mflac"),
+        };
+        let probed = Context {
+            ekey: Some(ekey),
+            ..context(dir.path(), &output)
+        };
+        let probe = probed.probe(&task).unwrap();
+        assert_eq!(probe.action, Action::Decrypt(KeySource::Argument));
+        // the key selected by the probe is used, not looked up again
+        let context = context(dir.path(), &output);
+        let mut record = Record::new(&task.path);
+        context
+            .process(&task, Some(&probe), &mut Log::default(), &mut record)
+            .unwrap();
+        assert_eq!(record.status, Status::Decrypted);
+        assert!(context
+            .process(&task, None, &mut Log::default(), &mut record)
+            .is_err());
+    }
+
+    #[test]
+    fn test_claimed_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, ekey) = encrypted(dir.path());
+        fs::copy(&path, dir.path().join("a.flac.mflac")).unwrap();
+        let output = dir.path().join("out");
+        let context = Context {
+            ekey: Some(ekey),
+            ..context(dir.path(), &output)
+        };
+        let tasks = ["a.flac.mflac", "a.mflac"].map(|name| Entry {
+            path: dir.path().join(name),
+            relative: PathBuf::from(name),
+        });
+        let mut log = Log::default();
+        let probes = tasks
+            .iter()
+            .map(|task| {
+                let mut probe = context.probe(task).unwrap();
+                probe.output = probe.output.map(|output| context.claim(output, &mut log).0);
+                probe
+            })
+            .collect::<Vec<_>>();
+        let targets = probes.iter().map(|probe| probe.output.clone().unwrap());
+        let targets = targets.collect::<Vec<_>>();
+        assert_eq!(targets, [output.join("a.flac"), output.join("a (2).flac")]);
+        // processed in any order, each task keeps the output claimed in task order
+        for ((task, probe), target) in tasks.iter().zip(&probes).zip(&targets).rev() {
+            let mut record = Record::new(&task.path);
+            context
+                .process(task, Some(probe), &mut log, &mut record)
+                .unwrap();
+            assert_eq!(record.output.as_ref(), Some(target));
+        }
+    }
+}
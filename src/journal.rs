@@ -0,0 +1,222 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::report::{Record, Status};
+use crate::utils;
+use anyhow::Result;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+/// Default file name of the journal, created in the output directory.
+pub const JOURNAL_NAME: &str = ".uqm-journal.db";
+
+/// Size, modification time and content hash of an input, taken before it is processed. The hash
+/// is only computed when needed, see [`Stamp::hash`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stamp {
+    pub size: u64,
+    pub mtime: i64,
+    pub hash: Option<String>,
+}
+
+impl Stamp {
+    pub fn of(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mtime = match metadata.modified()?.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_nanos() as i64,
+            Err(err) => -(err.duration().as_nanos() as i64),
+        };
+        Ok(Self {
+            size: metadata.len(),
+            mtime,
+            hash: None,
+        })
+    }
+
+    /// Hash the content of `path` unless already done, e.g. by [`Journal::is_done`].
+    pub fn hash(&mut self, path: &Path) -> Result<&str> {
+        if self.hash.is_none() {
+            self.hash = Some(utils::hash(path)?);
+        }
+        Ok(self.hash.as_deref().unwrap_or_default())
+    }
+}
+
+/// Progress of previous runs, so that an interrupted run can be resumed.
+///
+/// Every processed input is recorded with its [`Stamp`] and result. An input is done when its last
+/// result was not a failure, its output still exists, and it is unchanged: same size, and either
+/// the same mtime or, when only the mtime differs, the same hash. Inputs are hashed when they are
+/// processed, and again by a later run only when their mtime changed.
+pub struct Journal {
+    conn: Mutex<Connection>,
+}
+
+struct Entry {
+    stamp: Stamp,
+    status: String,
+    output: Option<PathBuf>,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = NORMAL;
+             CREATE TABLE IF NOT EXISTS journal (
+                 input TEXT PRIMARY KEY NOT NULL,
+                 size INTEGER NOT NULL,
+                 mtime INTEGER NOT NULL,
+                 hash TEXT,
+                 status TEXT NOT NULL,
+                 output TEXT
+             );",
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Open an existing journal without modifying it, e.g. for a dry run.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Whether `input` was handled by a previous run and has not changed since. The hash of
+    /// `input` is added to `stamp` when it had to be computed.
+    pub fn is_done(&self, input: &Path, stamp: &mut Stamp) -> Result<bool> {
+        let Some(entry) = self.get(input)? else {
+            return Ok(false);
+        };
+        if entry.status == Status::Failed.as_str() || entry.stamp.size != stamp.size {
+            return Ok(false);
+        }
+        if entry.output.is_some_and(|output| !output.exists()) {
+            return Ok(false);
+        }
+        if entry.stamp.mtime == stamp.mtime {
+            return Ok(true);
+        }
+        let hash = stamp.hash(input)?;
+        Ok(entry.stamp.hash.as_deref() == Some(hash))
+    }
+
+    /// Store the result of `record` with `stamp`, which should be hashed, see [`Stamp::hash`].
+    pub fn record(&self, stamp: &Stamp, record: &Record) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO journal (input, size, mtime, hash, status, output)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                record.input.to_string_lossy(),
+                stamp.size as i64,
+                stamp.mtime,
+                stamp.hash,
+                record.status.as_str(),
+                record
+                    .output
+                    .as_ref()
+                    .map(|output| output.to_string_lossy().into_owned()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get(&self, input: &Path) -> Result<Option<Entry>> {
+        let entry = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT size, mtime, hash, status, output FROM journal WHERE input = ?1",
+                params![input.to_string_lossy()],
+                |row| {
+                    Ok(Entry {
+                        stamp: Stamp {
+                            size: row.get::<_, i64>(0)? as u64,
+                            mtime: row.get(1)?,
+                            hash: row.get(2)?,
+                        },
+                        status: row.get(3)?,
+                        output: row.get::<_, Option<String>>(4)?.map(PathBuf::from),
+                    })
+                },
+            )
+            .optional()?;
+        Ok(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_done() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::open(&dir.path().join(JOURNAL_NAME)).unwrap();
+        let input = dir.path().join("a.mflac");
+        let output = dir.path().join("a.flac");
+        fs::write(&input, b"encrypted").unwrap();
+        fs::write(&output, b"decrypted").unwrap();
+        let mut stamp = Stamp::of(&input).unwrap();
+        assert!(!journal.is_done(&input, &mut stamp).unwrap());
+
+        let mut record = Record::new(&input);
+        record.status = Status::Decrypted;
+        record.output = Some(output.clone());
+        stamp.hash(&input).unwrap();
+        journal.record(&stamp, &record).unwrap();
+        let mut unchanged = Stamp::of(&input).unwrap();
+        assert!(journal.is_done(&input, &mut unchanged).unwrap());
+        // settled by the mtime, not hashed
+        assert_eq!(unchanged.hash, None);
+
+        // touched but identical content, settled by the hash of the first run
+        let mut touched = Stamp {
+            mtime: stamp.mtime + 1,
+            hash: None,
+            ..stamp.clone()
+        };
+        assert!(journal.is_done(&input, &mut touched).unwrap());
+        assert_eq!(touched.hash, stamp.hash);
+
+        // modified content
+        fs::write(&input, b"Encrypted").unwrap();
+        touched.hash = None;
+        assert!(!journal.is_done(&input, &mut touched).unwrap());
+        let mut resized = Stamp {
+            size: stamp.size + 1,
+            ..stamp.clone()
+        };
+        assert!(!journal.is_done(&input, &mut resized).unwrap());
+
+        // output removed
+        fs::write(&input, b"encrypted").unwrap();
+        journal.record(&stamp, &record).unwrap();
+        fs::remove_file(&output).unwrap();
+        assert!(!journal.is_done(&input, &mut stamp).unwrap());
+
+        // failures are retried
+        record.output = None;
+        record.fail(&anyhow::anyhow!("boom"));
+        journal.record(&stamp, &record).unwrap();
+        assert!(!journal.is_done(&input, &mut stamp).unwrap());
+    }
+}
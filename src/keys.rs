@@ -0,0 +1,665 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::song::SongIndex;
+use crate::utils::{common_suffix, components, normalize};
+use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use thiserror::Error;
+use umc_qmc::ekey::{self, EKeyVersion};
+use umc_qmc::footer::media_mid_of;
+
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("{filename} matches {} key entries by {by} with different keys: {}", .paths.len(), .paths.join(", "))]
+    Ambiguous {
+        filename: String,
+        by: &'static str,
+        paths: Vec<String>,
+    },
+}
+
+/// Order of the entries matching a lookup, lowest first.
+type Rank = (Reverse<usize>, usize);
+
+/// An entry of a key source.
+#[derive(Debug)]
+struct KeyEntry {
+    /// Path as given by the source, or only a file name for key files.
+    path: String,
+    /// Path components, whichever separator the path uses.
+    components: Vec<String>,
+    media_mid: Option<String>,
+    ekey: String,
+    /// Index of the source in [`KeyStore::sources`], lower ones take precedence.
+    source: usize,
+}
+
+/// What is known of an encrypted file when looking up its key.
+#[derive(Debug, Clone, Copy)]
+pub struct Query<'a> {
+    pub path: &'a Path,
+    pub filename: &'a str,
+    /// Media mid found in the footer.
+    pub media_mid: Option<&'a str>,
+}
+
+/// The ekeys of every key source, indexed by full path, media mid and bare file name.
+///
+/// [`KeyStore::get`] tries these in that order. When a step finds entries with different keys,
+/// those whose path shares the most trailing components with the input are kept, then those of
+/// the source added first; if they still disagree the match is ambiguous and an error is
+/// returned rather than one of them. [`KeyStore::candidates`] returns all of them instead, to
+/// be told apart by trial decryption.
+///
+/// Sources are merged as they are added: an entry whose path is already known from an earlier
+/// source is dropped. When the keys differ, or when a bare file name of a key file has another
+/// key than an earlier entry of that name, a warning is added to [`KeyStore::conflicts`].
+#[derive(Debug, Default)]
+pub struct KeyStore {
+    entries: Vec<KeyEntry>,
+    sources: Vec<String>,
+    by_path: HashMap<String, Vec<usize>>,
+    by_media_mid: HashMap<String, Vec<usize>>,
+    by_filename: HashMap<String, Vec<usize>>,
+    conflicts: Vec<String>,
+}
+
+impl KeyStore {
+    /// Add the `audio_file_ekey_table` of a player database.
+    // noinspection SpellCheckingInspection
+    pub fn add_database(&mut self, path: &Path) -> Result<()> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let source = self.add_source(path);
+        let mut stmt = conn.prepare("SELECT file_path, ekey FROM audio_file_ekey_table")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            if let (Some(path), Some(ekey)) = (row.get(0)?, row.get(1)?) {
+                self.insert(source, path, ekey);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a key file of `file name, ekey` pairs, see [`read_key_file`].
+    pub fn add_file(&mut self, path: &Path) -> Result<()> {
+        let pairs = read_key_file(path)?;
+        let source = self.add_source(path);
+        for (path, ekey) in pairs {
+            self.insert(source, path, ekey);
+        }
+        Ok(())
+    }
+
+    fn add_source(&mut self, path: &Path) -> usize {
+        self.sources.push(path.to_string_lossy().into_owned());
+        self.sources.len() - 1
+    }
+
+    /// Add an entry of `source`. Paths without a file name are ignored.
+    fn insert(&mut self, source: usize, path: String, ekey: String) {
+        if path.ends_with(['/', '\\']) {
+            return;
+        }
+        let components = components(&path);
+        let Some(filename) = components.last().cloned() else {
+            return;
+        };
+        let normalized = normalize(&path);
+        let earlier = |i: &&usize| self.entries[**i].source != source;
+        let same_path = self.by_path.get(&normalized).into_iter().flatten();
+        let same_path = same_path.filter(earlier).copied().next();
+        // a bare file name stands for every path with that name
+        let same_name = self.by_filename.get(&filename).into_iter().flatten();
+        let same_name = same_name.filter(earlier).copied().find(|&i| {
+            let entry = &self.entries[i];
+            entry.ekey != ekey && (entry.components.len() == 1 || components.len() == 1)
+        });
+        if let Some(i) = same_path.or(same_name) {
+            let entry = &self.entries[i];
+            if entry.ekey != ekey {
+                self.conflicts.push(format!(
+                    "{}: the key of {} differs from the one of {}, which takes precedence",
+                    path, self.sources[source], self.sources[entry.source]
+                ));
+            }
+        }
+        if same_path.is_some() {
+            return;
+        }
+        let media_mid = media_mid_of(&filename).map(Into::into);
+        let i = self.entries.len();
+        self.by_path.entry(normalized).or_default().push(i);
+        self.by_filename.entry(filename).or_default().push(i);
+        if let Some(media_mid) = &media_mid {
+            self.by_media_mid
+                .entry(String::from(media_mid))
+                .or_default()
+                .push(i);
+        }
+        self.entries.push(KeyEntry {
+            path,
+            components,
+            media_mid,
+            ekey,
+            source,
+        });
+    }
+
+    /// Index the entries by the media mid the song tables give to their path, for files which
+    /// are not named after it.
+    pub fn link(&mut self, songs: &SongIndex) {
+        for song in songs.iter() {
+            let (Some(path), Some(media_mid)) = (&song.file_path, &song.media_mid) else {
+                continue;
+            };
+            for &i in self.by_path.get(&normalize(path)).into_iter().flatten() {
+                let entry = &mut self.entries[i];
+                if entry.media_mid.is_none() {
+                    entry.media_mid = Some(media_mid.clone());
+                    self.by_media_mid
+                        .entry(media_mid.clone())
+                        .or_default()
+                        .push(i);
+                }
+            }
+        }
+    }
+
+    /// The ekey of the file described by `query`, `None` when no entry matches.
+    pub fn get(&self, query: Query) -> Result<Option<&str>, KeyError> {
+        let Some((by, ranked)) = self.lookup(query) else {
+            return Ok(None);
+        };
+        let best = ranked[0].0;
+        let top = ranked
+            .iter()
+            .filter(|(rank, _)| *rank == best)
+            .map(|&(_, entry)| entry)
+            .collect::<Vec<_>>();
+        match same_key(&top) {
+            true => Ok(Some(&top[0].ekey)),
+            false => Err(KeyError::Ambiguous {
+                filename: query.filename.to_string(),
+                by,
+                paths: top.iter().map(|entry| entry.path.clone()).collect(),
+            }),
+        }
+    }
+
+    /// Every distinct ekey matching `query`, the one [`KeyStore::get`] would pick first, so
+    /// that they can be tried in turn.
+    pub fn candidates(&self, query: Query) -> Vec<&str> {
+        let mut ekeys = Vec::new();
+        for (_, entry) in self
+            .lookup(query)
+            .map(|(_, ranked)| ranked)
+            .unwrap_or_default()
+        {
+            if !ekeys.contains(&entry.ekey.as_str()) {
+                ekeys.push(entry.ekey.as_str());
+            }
+        }
+        ekeys
+    }
+
+    /// Paths and ekeys of every entry, in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry.ekey.as_str()))
+    }
+
+    /// Keys which differ from the ones of an earlier source, see [`KeyStore`].
+    pub fn conflicts(&self) -> &[String] {
+        &self.conflicts
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entries of the first step matching `query`, best first: those sharing the most trailing
+    /// path components with the input, then those of the earliest source.
+    fn lookup(&self, query: Query) -> Option<(&'static str, Vec<(Rank, &KeyEntry)>)> {
+        let steps = [
+            (
+                "path",
+                self.by_path.get(&normalize(&query.path.to_string_lossy())),
+            ),
+            (
+                "media mid",
+                query
+                    .media_mid
+                    .and_then(|media_mid| self.by_media_mid.get(media_mid)),
+            ),
+            ("file name", self.by_filename.get(query.filename)),
+        ];
+        let (by, candidates) = steps
+            .into_iter()
+            .find_map(|(by, candidates)| Some((by, candidates?)).filter(|(_, c)| !c.is_empty()))?;
+        let components = components(&query.path.to_string_lossy());
+        let mut ranked = candidates
+            .iter()
+            .map(|&i| {
+                let entry = &self.entries[i];
+                let suffix = common_suffix(&entry.components, &components);
+                ((Reverse(suffix), entry.source), entry)
+            })
+            .collect::<Vec<_>>();
+        ranked.sort_by_key(|&(rank, _)| rank);
+        Some((by, ranked))
+    }
+}
+
+/// Format of a key file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KeyFormat {
+    Csv,
+    Tsv,
+    Json,
+}
+
+impl KeyFormat {
+    /// Format of a key file by its extension.
+    pub fn of(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("csv") => Ok(KeyFormat::Csv),
+            Some("tsv") => Ok(KeyFormat::Tsv),
+            Some("json") => Ok(KeyFormat::Json),
+            _ => bail!(
+                "{:?}: unknown key file format, expected a .csv, .tsv or .json file",
+                path
+            ),
+        }
+    }
+
+    fn delimiter(self) -> char {
+        match self {
+            KeyFormat::Tsv => '\t',
+            _ => ',',
+        }
+    }
+}
+
+/// A line of a key file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeyRecord {
+    /// File name or full path of the audio file.
+    pub file_path: String,
+    pub ekey: String,
+    /// Length of the decrypted key, `None` when it could not be decrypted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_length: Option<usize>,
+    /// `v1`, or `v2` for ekeys prefixed by "QQMusic EncV2,Key:".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<&'static str>,
+}
+
+impl KeyRecord {
+    pub fn new(file_path: &str, ekey: &str) -> Self {
+        Self {
+            file_path: file_path.to_string(),
+            ekey: ekey.to_string(),
+            key_length: None,
+            envelope: None,
+        }
+    }
+
+    /// Fill in the key length and envelope of the ekey.
+    pub fn with_details(self) -> Self {
+        let envelope = match EKeyVersion::detect(&self.ekey) {
+            EKeyVersion::V1 => "v1",
+            EKeyVersion::V2 => "v2",
+        };
+        Self {
+            key_length: ekey::decrypt(&self.ekey).ok().map(|key| key.len()),
+            envelope: Some(envelope),
+            ..self
+        }
+    }
+}
+
+/// Read the `file name, ekey` pairs of a key file, by its extension:
+///
+/// - `.csv` and `.tsv`: one pair per line, separated by a comma or a tab. Blank lines and lines
+///   starting with `#` are skipped, and fields may be double-quoted. A header line naming an
+///   `ekey` column and a `filename` or `file_path` column selects these columns, without one
+///   the last field is the ekey.
+/// - `.json`: either an object mapping file names to ekeys, or an array of objects with a
+///   `filename` (or `file_path`) and an `ekey` field.
+///
+/// The file name may also be a full path, which is then matched like database paths.
+pub fn read_key_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let format = KeyFormat::of(path)?;
+    let content = fs::read_to_string(path)?;
+    let pairs = match format {
+        KeyFormat::Csv | KeyFormat::Tsv => read_delimited(&content, format.delimiter()),
+        KeyFormat::Json => read_json(&content),
+    };
+    pairs.map_err(|err| anyhow!("{:?}: {}", path, err))
+}
+
+/// Write `records` as a key file that [`read_key_file`] reads back.
+pub fn write_key_file<W: Write>(
+    writer: &mut W,
+    format: KeyFormat,
+    records: &[KeyRecord],
+) -> Result<()> {
+    if format == KeyFormat::Json {
+        serde_json::to_writer_pretty(&mut *writer, records)?;
+        writeln!(writer)?;
+        return Ok(());
+    }
+    let details = records.iter().any(|record| record.envelope.is_some());
+    let mut header = vec!["file_path", "ekey"];
+    if details {
+        header.extend(["key_length", "envelope"]);
+    }
+    let delimiter = format.delimiter();
+    writeln!(writer, "{}", header.join(&delimiter.to_string()))?;
+    for record in records {
+        let mut fields = vec![quote(&record.file_path, delimiter), record.ekey.clone()];
+        if details {
+            fields.push(record.key_length.map(|n| n.to_string()).unwrap_or_default());
+            fields.push(record.envelope.unwrap_or_default().to_string());
+        }
+        writeln!(writer, "{}", fields.join(&delimiter.to_string()))?;
+    }
+    Ok(())
+}
+
+fn read_delimited(content: &str, delimiter: char) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    let mut columns = None;
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = split(line, delimiter);
+        let position = |names: &[&str]| {
+            fields
+                .iter()
+                .position(|field| names.iter().any(|name| field.eq_ignore_ascii_case(name)))
+        };
+        if pairs.is_empty() && columns.is_none() {
+            if let Some(ekey) = position(&["ekey"]) {
+                let filename = position(&["filename", "file_path"]).unwrap_or(0);
+                columns = Some((filename, ekey));
+                continue;
+            }
+        }
+        let (filename, ekey) = match columns {
+            Some((filename, ekey)) => (fields.get(filename).cloned(), fields.get(ekey).cloned()),
+            None if fields.len() == 2 => (Some(fields[0].clone()), Some(fields[1].clone())),
+            // an unquoted file name containing the delimiter, ekeys are base64 and never do
+            None => match line.rsplit_once(delimiter) {
+                Some((filename, ekey)) => (Some(unquote(filename)), Some(unquote(ekey))),
+                None => (None, None),
+            },
+        };
+        match (filename, ekey) {
+            (Some(filename), Some(ekey)) if !filename.is_empty() && !ekey.is_empty() => {
+                pairs.push((filename, ekey))
+            }
+            _ => bail!("line {}: expected a file name and an ekey", n + 1),
+        }
+    }
+    Ok(pairs)
+}
+
+/// Split a line into trimmed fields, honouring double quotes.
+fn split(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => {
+                fields.push(field.trim().to_string());
+                field.clear();
+            }
+            c => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+fn unquote(field: &str) -> String {
+    let field = field.trim();
+    match field
+        .strip_prefix('"')
+        .and_then(|field| field.strip_suffix('"'))
+    {
+        Some(field) => field.replace("\"\"", "\""),
+        None => field.to_string(),
+    }
+}
+
+/// Quote a field which would otherwise not be read back as is.
+fn quote(field: &str, delimiter: char) -> String {
+    let needs_quotes = field.contains([delimiter, '"', '\n', '\r'])
+        || field.starts_with('#')
+        || field.trim() != field;
+    match needs_quotes {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+fn read_json(content: &str) -> Result<Vec<(String, String)>> {
+    let field = |value: &Value, names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| value.get(name).and_then(Value::as_str))
+            .map(String::from)
+    };
+    match serde_json::from_str(content)? {
+        Value::Object(map) => map
+            .into_iter()
+            .map(|(filename, ekey)| match ekey {
+                Value::String(ekey) => Ok((filename, ekey)),
+                _ => bail!("the ekey of {} is not a string", filename),
+            })
+            .collect(),
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| {
+                match (
+                    field(item, &["filename", "file_path"]),
+                    field(item, &["ekey"]),
+                ) {
+                    (Some(filename), Some(ekey)) => Ok((filename, ekey)),
+                    _ => bail!("entry {}: expected a filename and an ekey", i),
+                }
+            })
+            .collect(),
+        _ => bail!("expected an object or an array"),
+    }
+}
+
+fn same_key(entries: &[&KeyEntry]) -> bool {
+    entries.windows(2).all(|pair| pair[0].ekey == pair[1].ekey)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> KeyStore {
+        let mut store = KeyStore::default();
+        let source = store.add_source(Path::new("player_process_db"));
+        for (path, ekey) in [
+            ("/sdcard/qqmusic/song/a.mflac", "k1"),
+            ("/sdcard/qqmusic/other/a.mflac", "k2"),
+            ("/sdcard/qqmusic/backup/a.mflac", "k1"),
+            ("C:\\Music\\F0M0001y7CaR29k6YP.mflac", "k3"),
+            ("/sdcard/qqmusic/song/b.mgg", "k4"),
+            ("/sdcard/qqmusic/song/", "k5"),
+        ] {
+            store.insert(source, path.into(), ekey.into());
+        }
+        store
+    }
+
+    fn query<'a>(path: &'a str, media_mid: Option<&'a str>) -> Query<'a> {
+        let path = Path::new(path);
+        Query {
+            path,
+            filename: path.file_name().unwrap().to_str().unwrap(),
+            media_mid,
+        }
+    }
+
+    #[test]
+    fn test_lookup_order() {
+        let store = store();
+        assert_eq!(store.len(), 5);
+        let key = |path, media_mid| store.get(query(path, media_mid)).unwrap();
+        assert_eq!(key("/sdcard/qqmusic/other/a.mflac", None), Some("k2"));
+        assert_eq!(key("/in/x.mflac", Some("001y7CaR29k6YP")), Some("k3"));
+        assert_eq!(key("/in/b.mgg", Some("002aaaaaaaaaaa")), Some("k4"));
+        assert_eq!(key("/in/other/a.mflac", None), Some("k2"));
+        assert_eq!(key("/in/c.mgg", None), None);
+    }
+
+    #[test]
+    fn test_ambiguous() {
+        let mut store = store();
+        let err = store.get(query("/in/a.mflac", None)).unwrap_err();
+        let KeyError::Ambiguous { by, paths, .. } = err;
+        assert_eq!(by, "file name");
+        assert_eq!(paths.len(), 3);
+        // entries agreeing on the key are not ambiguous
+        store.insert(0, "/x/b.mgg".into(), "k4".into());
+        assert!(store.get(query("/in/b.mgg", None)).is_ok());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let store = store();
+        assert_eq!(
+            store.candidates(query("/in/other/a.mflac", None)),
+            vec!["k2", "k1"]
+        );
+        assert_eq!(
+            store.candidates(query("/in/a.mflac", None)),
+            vec!["k1", "k2"]
+        );
+        assert!(store.candidates(query("/in/c.mgg", None)).is_empty());
+    }
+
+    #[test]
+    fn test_precedence() {
+        let mut store = store();
+        let source = store.add_source(Path::new("keys.csv"));
+        store.insert(source, "/sdcard/qqmusic/song/b.mgg".into(), "k6".into());
+        store.insert(source, "c.mgg".into(), "k7".into());
+        store.insert(source, "/elsewhere/b.mgg".into(), "k8".into());
+        assert_eq!(store.conflicts().len(), 1);
+        store.insert(source, "b.mgg".into(), "k9".into());
+        assert_eq!(store.conflicts().len(), 2);
+        assert_eq!(store.get(query("/in/c.mgg", None)).unwrap(), Some("k7"));
+        // the first source wins over later ones, but not over a closer path
+        assert_eq!(store.get(query("/in/b.mgg", None)).unwrap(), Some("k4"));
+        assert_eq!(
+            store.get(query("/elsewhere/b.mgg", None)).unwrap(),
+            Some("k8")
+        );
+    }
+
+    #[test]
+    fn test_key_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, content: &str| {
+            let path = dir.path().join(name);
+            fs::write(&path, content).unwrap();
+            path
+        };
+        let expected = vec![
+            ("a, b.mflac".to_string(), "k1".to_string()),
+            ("c.mgg".to_string(), "k2".to_string()),
+        ];
+        let csv = write(
+            "keys.csv",
+            "filename,ekey\n\"a, b.mflac\",k1\r\n\n# c\nc.mgg, k2\n",
+        );
+        assert_eq!(read_key_file(&csv).unwrap(), expected);
+        let tsv = write("keys.tsv", "a, b.mflac\tk1\nc.mgg\tk2\n");
+        assert_eq!(read_key_file(&tsv).unwrap(), expected);
+        let json = write(
+            "keys.json",
+            r#"[{"filename": "a, b.mflac", "ekey": "k1"}, {"file_path": "c.mgg", "ekey": "k2"}]"#,
+        );
+        assert_eq!(read_key_file(&json).unwrap(), expected);
+        let json = write("map.json", r#"{"a, b.mflac": "k1", "c.mgg": "k2"}"#);
+        let mut pairs = read_key_file(&json).unwrap();
+        pairs.sort();
+        assert_eq!(pairs, expected);
+        assert!(read_key_file(&write("bad.csv", "a.mflac\n")).is_err());
+        assert!(read_key_file(&write("keys.txt", "a.mflac,k1\n")).is_err());
+    }
+
+    #[test]
+    fn test_write_key_file() {
+        let records = vec![
+            KeyRecord::new("/sdcard/a, \"b\".mflac", "k1").with_details(),
+            KeyRecord::new("#c.mgg", "k2").with_details(),
+        ];
+        assert_eq!(records[0].envelope, Some("v1"));
+        assert_eq!(records[0].key_length, None);
+        let dir = tempfile::tempdir().unwrap();
+        for (name, format) in [
+            ("keys.csv", KeyFormat::Csv),
+            ("keys.tsv", KeyFormat::Tsv),
+            ("keys.json", KeyFormat::Json),
+        ] {
+            let path = dir.path().join(name);
+            let mut content = Vec::new();
+            write_key_file(&mut content, format, &records).unwrap();
+            fs::write(&path, content).unwrap();
+            let pairs = read_key_file(&path).unwrap();
+            assert_eq!(
+                pairs,
+                vec![
+                    ("/sdcard/a, \"b\".mflac".to_string(), "k1".to_string()),
+                    ("#c.mgg".to_string(), "k2".to_string()),
+                ]
+            );
+        }
+    }
+}
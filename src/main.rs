@@ -11,146 +11,815 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
-use anyhow::{bail, Result};
-use clap::Parser;
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, ColorChoice, CommandFactory, Parser, Subcommand};
+use name::{OnConflict, Resolution};
 use std::fs;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use umc_qmc::{footer, QMCv2Cipher};
-
-const BUFFER_SIZE: usize = 4 * 1024 * 1024;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
 
+mod atomic;
+mod check;
+mod config;
+mod docs;
+mod inspect;
+mod job;
+mod journal;
+mod keys;
+mod name;
+mod pool;
+mod report;
+mod song;
+mod stream;
+mod tag;
+mod term;
+mod transfer;
 mod utils;
+mod verify;
+mod walk;
+
+/// Every file was processed.
+pub const EXIT_SUCCESS: i32 = 0;
+/// Some files failed, the others were processed.
+pub const EXIT_PARTIAL: i32 = 1;
+/// Invalid configuration, e.g. an unreadable `--db`, `--keys` or input directory; nothing was processed.
+/// This is also the code of command line usage errors.
+pub const EXIT_CONFIG: i32 = 2;
+/// No file could be processed.
+pub const EXIT_FAILURE: i32 = 3;
+
+/// The input or output path standing for the standard input or output.
+const STREAM: &str = "-";
+
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  every file was processed
+  1  some files failed, the others were processed
+  2  configuration error (bad arguments, --db, --keys, input or output directory)
+  3  every file failed";
+
+pub fn exit_code(succeeded: usize, failed: usize) -> i32 {
+    match (succeeded, failed) {
+        (_, 0) => EXIT_SUCCESS,
+        (0, _) => EXIT_FAILURE,
+        _ => EXIT_PARTIAL,
+    }
+}
 
 #[derive(Parser)]
-#[command(version, about)]
+#[command(name = "uqm", version, about, after_help = EXIT_CODES_HELP)]
 struct Cli {
+    #[command(flatten)]
+    global: GlobalArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Args)]
+pub struct GlobalArgs {
     /// Verbose printing
-    #[arg(short, long, default_value_t = false)]
-    verbose: bool,
+    #[arg(short, long, global = true, default_value_t = false)]
+    pub verbose: bool,
+
+    /// Only print errors, not warnings or summaries
+    #[arg(
+        short,
+        long,
+        global = true,
+        default_value_t = false,
+        conflicts_with = "verbose"
+    )]
+    pub quiet: bool,
+
+    /// Color warnings and verdicts
+    #[arg(long, global = true, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Read default options from this file instead of $XDG_CONFIG_HOME/uqm/config.toml
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Use the defaults of this profile of the configuration
+    #[arg(long, global = true, value_name = "NAME")]
+    pub profile: Option<String>,
+}
+
+/// Where the keys come from, for the subcommands which look them up.
+#[derive(Args)]
+pub struct KeySourceArgs {
+    /// A player database containing QMCv2 encryption keys, may be repeated
+    #[arg(short = 'D', long = "db", value_name = "FILE")]
+    pub db: Vec<PathBuf>,
+
+    /// A CSV, TSV or JSON file of file names and ekeys, may be repeated
+    #[arg(short, long, value_name = "FILE")]
+    pub keys: Vec<PathBuf>,
+}
 
-    /// The output directory
+impl KeySourceArgs {
+    /// Use the key sources of the configuration unless given on the command line.
+    pub fn apply(&mut self, settings: &config::Settings) {
+        if self.db.is_empty() {
+            self.db = settings.db.clone().unwrap_or_default();
+        }
+        if self.keys.is_empty() {
+            self.keys = settings.keys.clone().unwrap_or_default();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.db.is_empty() && self.keys.is_empty()
+    }
+
+    /// Load every source, see [`load_keys`].
+    pub fn load(&self) -> Result<(keys::KeyStore, song::SongIndex)> {
+        load_keys(&self.keys, &self.db)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Decrypt a directory, a single file, or the standard input
+    #[command(after_help = EXIT_CODES_HELP)]
+    Decrypt(DecryptArgs),
+    /// Report the footer, key source and cipher of files without decrypting them
+    Inspect(inspect::InspectArgs),
+    /// Export ekeys to a key file, or merge key files into a key store
+    Keys(transfer::KeysArgs),
+    /// Check that decrypted files are complete, valid audio streams
+    Verify(check::VerifyArgs),
+    /// Summarize which files of a directory can be decrypted with the known keys
+    Scan(inspect::ScanArgs),
+    /// Print the completion script of a shell
+    Completions(docs::CompletionsArgs),
+    /// Print the man page, or write the pages of every subcommand to a directory
+    Man(docs::ManArgs),
+}
+
+#[derive(Args)]
+struct DecryptArgs {
+    /// The output directory, or - to write a single decrypted input to the standard output
     #[arg(short, long, value_name = "DIR")]
     output: Option<PathBuf>,
 
-    /// The database containing the QMCv2 encryption keys
-    #[arg(short = 'D', long = "db")]
-    db: PathBuf,
+    #[command(flatten)]
+    sources: KeySourceArgs,
+
+    /// The ekey of a single input file or of the standard input, used instead of any other key
+    #[arg(long, value_name = "EKEY")]
+    ekey: Option<String>,
 
     /// Replace original files
     #[arg(short, long, default_value_t = false)]
     replace: bool,
 
-    /// The input directory
+    /// What to do with outputs which already exist [default: overwrite]
+    #[arg(long, value_name = "POLICY")]
+    on_conflict: Option<OnConflict>,
+
+    /// Keep original files, even when the configuration replaces them
+    #[arg(long, default_value_t = false, overrides_with = "replace")]
+    no_replace: bool,
+
+    /// Walk subdirectories and mirror their layout under the output directory
+    #[arg(short = 'R', long, default_value_t = false)]
+    recursive: bool,
+
+    /// Number of files decrypted in parallel [default: number of CPUs]
+    #[arg(short, long, value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Name outputs by the extension table instead of the detected audio type
+    #[arg(long, default_value_t = false)]
+    keep_ext: bool,
+
+    /// Name the outputs of an encrypted extension with this extension, e.g. mgg=opus, may be repeated
+    #[arg(long = "ext", value_name = "EXT=AUDIO_EXT", value_parser = parse_extension)]
+    extensions: Vec<(String, String)>,
+
+    /// Stop at the first failing file instead of processing the rest
+    #[arg(long, default_value_t = false)]
+    fail_fast: bool,
+
+    /// Write a NDJSON record per file, followed by a summary, to this file
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Skip inputs which a previous run already handled and which have not changed since
+    #[arg(long, default_value_t = false)]
+    resume: bool,
+
+    /// Record progress in this journal, used by --resume [default: .uqm-journal.db in the output
+    /// directory, with --resume]
+    #[arg(long, value_name = "FILE")]
+    journal: Option<PathBuf>,
+
+    /// Name outputs with placeholders such as {title} or {media_mid}, see the README
+    #[arg(long, value_name = "TEMPLATE")]
+    name_template: Option<name::NameTemplate>,
+
+    /// Write the title, artist, album and song ids into the decrypted files
+    #[arg(long, default_value_t = false)]
+    tag: bool,
+
+    /// Parse the decrypted audio and mark every output as verified, suspicious or broken
+    #[arg(long, default_value_t = false)]
+    verify: bool,
+
+    /// Use the first matching key without checking that it decrypts to a known audio stream
+    #[arg(long, default_value_t = false)]
+    no_key_check: bool,
+
+    /// Print the planned action and output of every file without writing anything
+    #[arg(long, default_value_t = false, conflicts_with = "report")]
+    dry_run: bool,
+
+    /// Upper limit of the memory used by decryption buffers, in MiB
+    #[arg(long, value_name = "MIB", default_value_t = 64)]
+    max_memory: usize,
+
+    /// The input directory, a single file, or - for the standard input
     input: PathBuf,
 }
 
+/// A task checked against the journal and probed, see `DecryptArgs::prepare`.
+struct Prepared<'a> {
+    /// Taken when a journal is kept.
+    stamp: Option<Result<journal::Stamp>>,
+    /// Handled by a previous run and unchanged since.
+    done: bool,
+    /// Key and output of the task, its output claimed; `None` when done or probing failed.
+    probe: Option<job::Probe<'a>>,
+}
+
 impl Cli {
-    // noinspection SpellCheckingInspection
-    pub fn run(&self) -> Result<i32> {
-        if !fs::metadata(&self.input)?.is_dir() {
-            bail!("{:?} is not a directory", &self.input);
-        }
-        let tasks: Vec<PathBuf> = fs::read_dir(&self.input)?
-            .filter_map(|entry| entry.ok())
-            .map(|entry| entry.path())
-            .map(|path| fs::canonicalize(path).unwrap())
-            .collect();
-        let database = utils::load_db(&self.db)?;
+    pub fn run(self) -> Result<i32> {
+        term::init(self.global.quiet, self.global.color);
+        let verbose = self.global.verbose;
+        let settings = || {
+            config::load(
+                self.global.config.as_deref(),
+                self.global.profile.as_deref(),
+            )
+        };
+        match self.command {
+            Command::Decrypt(mut args) => {
+                args.apply(settings()?)?;
+                args.run(verbose)
+            }
+            Command::Inspect(mut args) => {
+                args.sources.apply(&settings()?);
+                args.run()
+            }
+            Command::Keys(args) => args.run(settings),
+            Command::Verify(args) => args.run(&self.global),
+            Command::Scan(mut args) => {
+                args.sources.apply(&settings()?);
+                args.run(&self.global)
+            }
+            Command::Completions(args) => args.run(Cli::command()),
+            Command::Man(args) => args.run(Cli::command()),
+        }
+    }
+}
+
+impl DecryptArgs {
+    /// Fill the options which are not given on the command line from the configuration.
+    fn apply(&mut self, settings: config::Settings) -> Result<()> {
+        self.sources.apply(&settings);
+        self.output = self.output.take().or(settings.output);
+        self.jobs = self.jobs.or(settings.jobs);
+        self.on_conflict = self.on_conflict.or(settings.on_conflict);
+        // options which cannot be streamed are not applied when streaming
+        if !self.streaming() {
+            if self.name_template.is_none() {
+                self.name_template = match settings.name_template {
+                    Some(template) => Some(template.parse().map_err(|err| anyhow!("{}", err))?),
+                    None => None,
+                };
+            }
+            if !self.replace && !self.no_replace {
+                self.replace = settings.replace.unwrap_or(false);
+            }
+        }
+        for audio_extension in settings.extensions.values() {
+            check_audio_extension(audio_extension).map_err(|err| anyhow!("extensions: {}", err))?;
+        }
+        let extensions = settings
+            .extensions
+            .into_iter()
+            .chain(self.extensions.drain(..));
+        self.extensions = extensions.collect();
+        Ok(())
+    }
+
+    /// Whether the input or the output is a stream rather than files.
+    fn streaming(&self) -> bool {
+        self.input == Path::new(STREAM) || self.output.as_deref() == Some(Path::new(STREAM))
+    }
+
+    pub fn run(&self, verbose: bool) -> Result<i32> {
+        if self.streaming() {
+            return self.stream(verbose);
+        }
+        // a single file is processed as the only entry of its directory
+        let single = !fs::metadata(&self.input)?.is_dir();
+        let input = match single {
+            true => match self.input.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            },
+            false => self.input.clone(),
+        };
+        if self.ekey.is_some() && !single {
+            bail!("--ekey requires a single input file");
+        }
+        let (keys, songs) = self.sources.load()?;
+        if verbose && !keys.is_empty() {
+            println!("{} key(s) found", keys.len());
+        }
+        if verbose && !songs.is_empty() {
+            println!("{} song(s) found in the database", songs.len());
+        }
         let output: PathBuf = match &self.output {
-            Some(path) => match fs::metadata(&path) {
+            Some(path) => match fs::metadata(path) {
                 Ok(metadata) => {
                     if !metadata.is_dir() {
                         bail!("{:?} is not a directory", &path);
                     }
                     path.clone()
                 }
+                Err(_) if self.dry_run => path.clone(),
                 Err(_) => {
-                    fs::create_dir_all(&path)?;
+                    fs::create_dir_all(path)?;
                     path.clone()
                 }
             },
-            None => self.input.clone(),
+            None => input.clone(),
         };
-        for task in tasks {
-            if self.verbose {
-                println!("processing {:?}", task);
-            }
-            let mut file = File::open(&task)?;
-            let filename = utils::get_filename(&task)?;
-            if !filename.contains(".mflac2") {
-                if output != self.input {
-                    let mut target = output.clone();
-                    target.push(filename.clone());
-                    fs::copy(&task, &target)?;
+        let reporter = match &self.report {
+            Some(path) => Some(report::Reporter::create(path)?),
+            None => None,
+        };
+        let journal_path = match &self.journal {
+            Some(path) => path.clone(),
+            None => output.join(journal::JOURNAL_NAME),
+        };
+        let journal = match (self.dry_run, self.resume || self.journal.is_some()) {
+            (_, false) => None,
+            (false, true) => Some(journal::Journal::open(&journal_path)?),
+            (true, true) if self.resume && journal_path.exists() => {
+                Some(journal::Journal::open_read_only(&journal_path)?)
+            }
+            (true, true) => None,
+        };
+        let mut tasks = match single {
+            true => vec![walk::Entry {
+                path: fs::canonicalize(&self.input)?,
+                relative: PathBuf::from(utils::get_filename(&self.input)?),
+            }],
+            false => {
+                let exclude = Some(output.as_path()).filter(|&path| path != input);
+                walk::collect(&input, exclude, self.recursive, verbose)?
+            }
+        };
+        // the journal and its WAL files may live in the input directory
+        if let Ok(journal_path) = fs::canonicalize(&journal_path) {
+            let journal_path = journal_path.to_string_lossy();
+            tasks.retain(|task| {
+                !task
+                    .path
+                    .to_string_lossy()
+                    .starts_with(journal_path.as_ref())
+            });
+        }
+        let jobs = self.jobs.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        });
+        let context = self.context(input, output, keys, songs, verbose);
+        let missing = match single {
+            true => 0,
+            false => report_missing(&context.songs, &tasks, verbose),
+        };
+        if self.dry_run {
+            return Ok(self.dry_run(&context, &tasks, journal.as_ref()));
+        }
+        let started = Instant::now();
+        // files are probed and their outputs named by the workers, then claimed in task order, so
+        // that which input gets a ` (n)` suffix does not depend on the order in which the workers
+        // finish; the probes are kept, so keys are only selected once
+        let prepared = Mutex::new((0..tasks.len()).map(|_| None).collect::<Vec<_>>());
+        let indexed = tasks.iter().enumerate().collect::<Vec<_>>();
+        pool::run(jobs, &indexed, false, |&(i, task), log| {
+            let task = self.prepare(&context, journal.as_ref(), task, log);
+            prepared.lock().unwrap()[i] = Some(task);
+            Ok(())
+        });
+        let mut log = pool::Log::default();
+        let prepared = prepared
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|prepared| {
+                let mut prepared = prepared.expect("every task is prepared");
+                if let Some(probe) = &mut prepared.probe {
+                    probe.output = probe
+                        .output
+                        .take()
+                        .map(|output| context.claim(output, &mut log).0);
                 }
-                continue;
-            }
-            let mut buffer = vec![0u8; footer::INITIAL_DETECTION_LEN];
-            file.seek(SeekFrom::End(-(footer::INITIAL_DETECTION_LEN as i64)))?;
-            file.read_exact(&mut buffer)?;
-            let size = file.stream_position()?;
-            file.seek(SeekFrom::Start(0))?;
-            let (footer_size, ekey) = match footer::from_byte_slice(&buffer) {
-                Ok(Some(metadata)) => {
-                    if self.verbose {
-                        println!("{}: {:?}", filename, metadata);
+                prepared
+            })
+            .collect::<Vec<_>>();
+        log.flush();
+        let summary = Mutex::new(report::Summary::default());
+        let work = tasks.iter().zip(prepared).collect::<Vec<_>>();
+        let results = pool::run(jobs, &work, self.fail_fast, |(task, prepared), log| {
+            let start = Instant::now();
+            let mut record = report::Record::new(&task.path);
+            let mut stamp = match (&journal, &prepared.stamp, prepared.done) {
+                (Some(_), Some(Ok(stamp)), false) => Some(stamp.clone()),
+                _ => None,
+            };
+            // hashed before processing, which may remove the input
+            if let Some(Err(err)) = stamp.as_mut().map(|stamp| stamp.hash(&task.path)) {
+                log.warn(format!("failed to hash {:?}: {}", task.path, err));
+            }
+            let result = match prepared.done {
+                true => {
+                    if verbose {
+                        log.out(format!("{:?} is unchanged since the last run", task.path));
                     }
-                    (metadata.size, metadata.ekey)
+                    record.status = report::Status::Unchanged;
+                    Ok(())
                 }
-                Ok(None) => {
-                    eprintln!("could not find any qmc metadata.");
-                    (0usize, None)
+                false => context.process(task, prepared.probe.as_ref(), log, &mut record),
+            };
+            if let Err(err) = &result {
+                log.err(format!("failed to process {:?}: {}", task.path, err));
+                record.fail(err);
+            }
+            record.elapsed_ms = start.elapsed().as_millis() as u64;
+            if let (Some(journal), Some(stamp)) = (&journal, &stamp) {
+                if let Err(err) = journal.record(stamp, &record) {
+                    log.warn(format!("failed to write journal: {}", err));
                 }
-                Err(err) => {
-                    eprintln!("failed to parse qmc metadata: {}", err);
-                    (0usize, None)
+            }
+            summary.lock().unwrap().add(&record);
+            if let Some(reporter) = &reporter {
+                if let Err(err) = reporter.record(&record) {
+                    log.warn(format!("failed to write report: {}", err));
+                }
+            }
+            result
+        });
+        let mut summary = summary.into_inner().unwrap();
+        summary.missing = missing;
+        summary.elapsed_ms = started.elapsed().as_millis() as u64;
+        if let Some(reporter) = &reporter {
+            reporter.summary(&summary)?;
+        }
+        let failures = tasks
+            .iter()
+            .zip(&results)
+            .filter_map(|(task, result)| match result {
+                Some(Err(err)) => Some((task, err)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let succeeded = results
+            .iter()
+            .filter(|result| matches!(result, Some(Ok(()))))
+            .count();
+        let skipped = tasks.len() - succeeded - failures.len();
+        if !failures.is_empty() {
+            term::error(format!(
+                "{} of {} file(s) failed:",
+                failures.len(),
+                tasks.len()
+            ));
+            for (task, err) in &failures {
+                eprintln!("  {:?}: {}", task.path, err);
+            }
+        }
+        if skipped > 0 {
+            term::info(format!(
+                "{} file(s) were not processed after the first failure",
+                skipped
+            ));
+        }
+        if self.verify {
+            term::info(format!(
+                "{} output(s) verified, {} suspicious, {} broken",
+                summary.verified, summary.suspicious, summary.broken
+            ));
+        }
+        Ok(exit_code(succeeded, failures.len()))
+    }
+
+    /// Check `task` against the journal and probe it, ahead of processing it.
+    fn prepare<'a>(
+        &self,
+        context: &'a job::Context,
+        journal: Option<&journal::Journal>,
+        task: &walk::Entry,
+        log: &mut pool::Log,
+    ) -> Prepared<'a> {
+        let mut stamp = journal.map(|_| journal::Stamp::of(&task.path));
+        let done = match (journal, &mut stamp) {
+            (Some(journal), Some(Ok(stamp))) if self.resume => {
+                journal.is_done(&task.path, stamp).unwrap_or_else(|err| {
+                    log.warn(format!("failed to read journal: {}", err));
+                    false
+                })
+            }
+            _ => false,
+        };
+        // a task which cannot be probed fails again in `process`, which reports why
+        let probe = match done {
+            true => None,
+            false => context.probe(task).ok(),
+        };
+        Prepared { stamp, done, probe }
+    }
+
+    fn context(
+        &self,
+        input: PathBuf,
+        output: PathBuf,
+        keys: keys::KeyStore,
+        songs: song::SongIndex,
+        verbose: bool,
+    ) -> job::Context {
+        job::Context {
+            input,
+            output,
+            keys,
+            budget: pool::Budget::new(self.max_memory * 1024 * 1024),
+            replace: self.replace,
+            keep_ext: self.keep_ext,
+            verbose,
+            name_template: self.name_template.clone(),
+            claims: name::Claims::default(),
+            songs,
+            tag: self.tag,
+            check_key: !self.no_key_check,
+            verify: self.verify,
+            ekey: self.ekey.clone(),
+            on_conflict: self.on_conflict.unwrap_or_default(),
+            extensions: self
+                .extensions
+                .iter()
+                .map(|(extension, audio_extension)| {
+                    (extension.to_lowercase(), audio_extension.clone())
+                })
+                .collect(),
+        }
+    }
+
+    /// Decrypt a single file or the standard input to the standard output.
+    ///
+    /// The standard input cannot be seeked, so its key must be given with `--ekey`, and its
+    /// footer is only found once the whole stream is read, see [`stream::decrypt`].
+    fn stream(&self, verbose: bool) -> Result<i32> {
+        for (set, option) in [
+            (self.replace, "--replace"),
+            (self.recursive, "--recursive"),
+            (self.resume, "--resume"),
+            (self.report.is_some(), "--report"),
+            (self.name_template.is_some(), "--name-template"),
+            (self.tag, "--tag"),
+            (self.verify, "--verify"),
+            (self.dry_run, "--dry-run"),
+        ] {
+            if set {
+                bail!(
+                    "{} cannot be used with the standard input or output",
+                    option
+                );
+            }
+        }
+        if self.output.as_deref() != Some(Path::new(STREAM)) {
+            bail!("decrypting the standard input requires `--output -`");
+        }
+        let mut writer = BufWriter::new(io::stdout().lock());
+        let result = match self.input == Path::new(STREAM) {
+            true => {
+                let Some(ekey) = &self.ekey else {
+                    bail!("decrypting the standard input requires --ekey");
+                };
+                let key = umc_qmc::ekey::decrypt(ekey).map_err(job::JobError::InvalidEKey)?;
+                let cipher = umc_qmc::QMCv2Cipher::new(key)?;
+                stream::decrypt(&mut io::stdin().lock(), &mut writer, &cipher)
+            }
+            false => {
+                if fs::metadata(&self.input)?.is_dir() {
+                    bail!("only a single file can be written to the standard output");
+                }
+                let (keys, songs) = self.sources.load()?;
+                let task = walk::Entry {
+                    path: fs::canonicalize(&self.input)?,
+                    relative: PathBuf::from(utils::get_filename(&self.input)?),
+                };
+                let context = self.context(PathBuf::new(), PathBuf::new(), keys, songs, verbose);
+                let mut log = pool::Log::default();
+                let result = context.stream(&task, &mut writer, &mut log);
+                log.flush_to_stderr();
+                result
+            }
+        };
+        match result {
+            Ok(_) => Ok(EXIT_SUCCESS),
+            // the reader, e.g. `head`, quit early
+            Err(err) if is_broken_pipe(&err) => Ok(EXIT_SUCCESS),
+            Err(err) => {
+                term::error(format!("failed to decrypt {:?}: {}", &self.input, err));
+                Ok(EXIT_FAILURE)
+            }
+        }
+    }
+
+    /// Plan every task, then report outputs claimed by more than one input as collisions.
+    fn dry_run(
+        &self,
+        context: &job::Context,
+        tasks: &[walk::Entry],
+        journal: Option<&journal::Journal>,
+    ) -> i32 {
+        let mut plans = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            let mut log = pool::Log::default();
+            let done = match (journal, journal::Stamp::of(&task.path)) {
+                (Some(journal), Ok(mut stamp)) => {
+                    journal.is_done(&task.path, &mut stamp).unwrap_or(false)
                 }
+                _ => false,
             };
-            let key: Vec<u8> = match ekey {
-                None => match database.get(&filename) {
-                    None => {
-                        eprintln!("could not find ekey for {}", filename);
-                        continue;
-                    }
-                    Some(ekey) => umc_qmc::ekey::decrypt(ekey)?,
-                },
-                Some(ekey) => umc_qmc::ekey::decrypt(ekey)?,
+            let plan = match done {
+                true => Ok(job::Plan {
+                    action: job::Action::Skip("unchanged since the last run"),
+                    output: None,
+                    renamed: false,
+                    conflict: None,
+                }),
+                false => context.plan(task, &mut log),
             };
-            let cipher = QMCv2Cipher::new(key)?;
-            let mut output = output.clone();
-            output.push(filename.clone().replace(".mflac2", ""));
-            let mut output = BufWriter::new(File::create(output)?);
-            let mut buffer = vec![0u8; BUFFER_SIZE];
-            let reader = BufReader::with_capacity(BUFFER_SIZE, file);
-            let mut reader = reader.take(size - footer_size as u64);
-            let mut offset = 0usize;
-            while let Ok(n) = reader.read(&mut buffer) {
-                if n == 0 {
-                    break;
+            log.flush();
+            plans.push(plan);
+        }
+
+        let (mut succeeded, mut failed, mut renamed) = (0, 0, 0);
+        for (task, plan) in tasks.iter().zip(&plans) {
+            match plan {
+                Ok(plan) => {
+                    match &plan.output {
+                        Some(output) => println!(
+                            "{:?}: {} -> {:?}{}",
+                            task.path,
+                            plan.action,
+                            output,
+                            match (plan.renamed, plan.conflict, context.on_conflict) {
+                                (true, ..) => " (renamed, name collision)",
+                                (false, Some(Resolution::Renamed), _) => {
+                                    " (renamed, the output exists)"
+                                }
+                                (
+                                    false,
+                                    Some(Resolution::Overwritten),
+                                    OnConflict::SkipIfIdentical,
+                                ) => " (overwrites an existing file unless identical)",
+                                (false, Some(Resolution::Overwritten), _) => {
+                                    " (overwrites an existing file)"
+                                }
+                                _ => "",
+                            }
+                        ),
+                        None => println!("{:?}: {}", task.path, plan.action),
+                    }
+                    if plan.renamed {
+                        renamed += 1;
+                    }
+                    succeeded += 1;
+                }
+                Err(err) => {
+                    println!("{:?}: fail ({})", task.path, err);
+                    failed += 1;
                 }
-                cipher.decrypt(&mut buffer[..n], offset);
-                output.write_all(&buffer[..n])?;
-                offset += n;
-            }
-            if self.replace {
-                fs::remove_file(task)?;
             }
-            if self.verbose {
-                println!("{} decrypted", filename)
+        }
+        if renamed > 0 {
+            term::warn(format!(
+                "{} output(s) would be renamed because another input maps to the same path",
+                renamed
+            ));
+        }
+        exit_code(succeeded, failed)
+    }
+}
+
+/// Merge the key files, then the databases, into one store; sources given first take
+/// precedence. Conflicting keys and missing song tables are printed as warnings.
+pub fn load_keys(
+    files: &[PathBuf],
+    databases: &[PathBuf],
+) -> Result<(keys::KeyStore, song::SongIndex)> {
+    let mut keys = keys::KeyStore::default();
+    let mut songs = song::SongIndex::default();
+    for path in files {
+        keys.add_file(path)?;
+    }
+    for path in databases {
+        keys.add_database(path)?;
+        songs.add_database(path)?;
+    }
+    keys.link(&songs);
+    for warning in keys.conflicts().iter().chain(songs.warnings()) {
+        term::warn(warning);
+    }
+    Ok((keys, songs))
+}
+
+/// Parse an `EXT=AUDIO_EXT` pair of `--ext`.
+fn parse_extension(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((extension, audio_extension))
+            if !extension.is_empty() && !audio_extension.is_empty() =>
+        {
+            let audio_extension = audio_extension.trim_start_matches('.');
+            check_audio_extension(audio_extension)?;
+            Ok((extension.to_string(), audio_extension.to_string()))
+        }
+        _ => Err(format!("expected EXT=AUDIO_EXT, got {:?}", value)),
+    }
+}
+
+/// Reject an output extension which is itself encrypted, which could name an output after its
+/// input, or have it decrypted again by a later run.
+fn check_audio_extension(audio_extension: &str) -> Result<(), String> {
+    let extension = audio_extension.trim_start_matches('.');
+    match umc_qmc::format::from_extension(extension) {
+        Some(_) => Err(format!("{:?} is an encrypted extension", extension)),
+        None => Ok(()),
+    }
+}
+
+fn is_broken_pipe(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe)
+    })
+}
+
+/// Print the files of the database which are not among `tasks`, returning their number.
+fn report_missing(songs: &song::SongIndex, tasks: &[walk::Entry], verbose: bool) -> usize {
+    let present = tasks
+        .iter()
+        .filter_map(|task| task.path.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect();
+    let missing = songs.missing(&present);
+    if !missing.is_empty() {
+        term::info(format!(
+            "{} file(s) in the database were not found in the input directory{}",
+            missing.len(),
+            if verbose { ":" } else { " (-v to list them)" }
+        ));
+        if verbose {
+            for path in &missing {
+                term::info(format!("  {}", path));
             }
         }
-        Ok(0)
     }
+    missing.len()
 }
 
 fn main() {
     let cli = Cli::parse();
-    let code = cli.run().unwrap_or_else(|err| {
-        eprintln!("run command failed: {}", err);
-        -1
+    let code = cli.run().unwrap_or_else(|err| match is_broken_pipe(&err) {
+        // the reader of the output, e.g. `head`, quit early
+        true => EXIT_SUCCESS,
+        false => {
+            term::error(format!("run command failed: {}", err));
+            EXIT_CONFIG
+        }
     });
     exit(code);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+    }
+}
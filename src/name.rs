@@ -0,0 +1,362 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Longest file or directory name produced by a template, in bytes.
+const MAX_COMPONENT_LEN: usize = 255;
+
+const PLACEHOLDERS: [(&str, Field); 8] = [
+    ("stem", Field::Stem),
+    ("ext", Field::Ext),
+    ("mid", Field::Mid),
+    ("media_mid", Field::MediaMid),
+    ("resource_id", Field::ResourceId),
+    ("title", Field::Title),
+    ("artist", Field::Artist),
+    ("album", Field::Album),
+];
+
+/// Values a [`NameTemplate`] is rendered with, `None` when unknown.
+#[derive(Debug, Default, Clone)]
+pub struct Fields {
+    /// Default output name without its extension.
+    pub stem: String,
+    /// Audio extension of the output.
+    pub ext: String,
+    pub mid: Option<String>,
+    pub media_mid: Option<String>,
+    pub resource_id: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Stem,
+    Ext,
+    Mid,
+    MediaMid,
+    ResourceId,
+    Title,
+    Artist,
+    Album,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+/// Output name pattern such as `{artist}/{album}/{title}.{ext}`.
+///
+/// `/` separates directories, created under the mirrored output directory. Values are sanitised
+/// so that they cannot add directories or characters that are invalid on common file systems,
+/// and `.{ext}` is appended when the template does not use `{ext}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    segments: Vec<Segment>,
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        if template.starts_with('/') {
+            return Err("the template must be a relative path".into());
+        }
+        if template
+            .split('/')
+            .any(|component| component.is_empty() || component == "." || component == "..")
+        {
+            return Err("the template contains an empty, `.` or `..` path component".into());
+        }
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed placeholder in {:?}", template))?;
+            let name = &rest[start + 1..start + end];
+            let field = PLACEHOLDERS
+                .iter()
+                .find(|(placeholder, _)| *placeholder == name)
+                .map(|(_, field)| *field)
+                .ok_or_else(|| {
+                    let known = PLACEHOLDERS.map(|(placeholder, _)| placeholder).join(", ");
+                    format!(
+                        "unknown placeholder {{{}}}, expected one of {}",
+                        name, known
+                    )
+                })?;
+            segments.push(Segment::Field(field));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        Ok(Self { segments })
+    }
+}
+
+impl NameTemplate {
+    /// Relative output path, or `None` when a placeholder in use has no value.
+    pub fn render(&self, fields: &Fields) -> Option<PathBuf> {
+        let mut name = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => name.push_str(literal),
+                Segment::Field(field) => name.push_str(&sanitize(fields.get(*field)?)),
+            }
+        }
+        if !self.segments.contains(&Segment::Field(Field::Ext)) {
+            name.push('.');
+            name.push_str(&sanitize(&fields.ext));
+        }
+        let components = name.split('/').collect::<Vec<_>>();
+        let last = components.len() - 1;
+        Some(
+            components
+                .iter()
+                .enumerate()
+                .map(|(i, component)| clean_component(component, i == last))
+                .collect(),
+        )
+    }
+}
+
+impl Fields {
+    fn get(&self, field: Field) -> Option<&str> {
+        match field {
+            Field::Stem => Some(self.stem.as_str()),
+            Field::Ext => Some(self.ext.as_str()),
+            Field::Mid => self.mid.as_deref(),
+            Field::MediaMid => self.media_mid.as_deref(),
+            Field::ResourceId => self.resource_id.as_deref(),
+            Field::Title => self.title.as_deref(),
+            Field::Artist => self.artist.as_deref(),
+            Field::Album => self.album.as_deref(),
+        }
+        .filter(|value| !value.trim().is_empty())
+    }
+}
+
+/// Replace path separators, characters reserved on Windows and control characters.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Trim a path component, which must not end with a dot or space on Windows, and shorten it to
+/// [`MAX_COMPONENT_LEN`] bytes, keeping the extension of the file name.
+fn clean_component(component: &str, is_file_name: bool) -> String {
+    let component = component.trim().trim_end_matches('.');
+    let (stem, ext) = match component.rfind('.') {
+        Some(i) if is_file_name && i > 0 => component.split_at(i),
+        _ => (component, ""),
+    };
+    let mut stem = stem.trim_end().to_string();
+    let limit = MAX_COMPONENT_LEN.saturating_sub(ext.len());
+    if stem.len() > limit {
+        let mut end = limit;
+        while !stem.is_char_boundary(end) {
+            end -= 1;
+        }
+        stem.truncate(end);
+    }
+    match stem.is_empty() {
+        true => format!("_{}", ext),
+        false => stem + ext,
+    }
+}
+
+/// What to do with an output which already exists on disk, see `--on-conflict`.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnConflict {
+    /// Keep the existing file and skip the input
+    Skip,
+    /// Replace the existing file
+    #[default]
+    Overwrite,
+    /// Write to the first free `name (n).ext` instead
+    Rename,
+    /// Fail the input
+    Fail,
+    /// Skip the input when the existing file has the same size and hash, replace it otherwise
+    SkipIfIdentical,
+}
+
+/// What was done about an output which already existed, see [`OnConflict`].
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    Overwritten,
+    Skipped,
+    Renamed,
+    /// Skipped, as the existing file has the same content.
+    Identical,
+}
+
+impl Display for Resolution {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Resolution::Overwritten => "overwritten",
+            Resolution::Skipped => "skipped",
+            Resolution::Renamed => "renamed",
+            Resolution::Identical => "identical, skipped",
+        })
+    }
+}
+
+/// Outputs claimed during a run, so that two inputs never write the same file.
+#[derive(Default)]
+pub struct Claims {
+    paths: Mutex<HashSet<PathBuf>>,
+}
+
+impl Claims {
+    /// Claim `path`, or the first free `stem (n).ext` variant when it is taken.
+    /// Also returns whether the path had to be changed.
+    pub fn claim(&self, path: PathBuf) -> (PathBuf, bool) {
+        let mut paths = self.paths.lock().unwrap();
+        if paths.insert(path.clone()) {
+            return (path, false);
+        }
+        let (stem, ext) = split_extension(&path);
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let path = (2..)
+            .map(|n| parent.join(format!("{} ({}){}", stem, n, ext)))
+            .find(|path| !paths.contains(path))
+            .expect("there is always a free name");
+        paths.insert(path.clone());
+        (path, true)
+    }
+
+    /// Claim the first `stem (n).ext` variant of `path` which is neither claimed nor on disk.
+    pub fn claim_free(&self, path: &Path) -> PathBuf {
+        let mut paths = self.paths.lock().unwrap();
+        let (stem, ext) = split_extension(path);
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let path = (2..)
+            .map(|n| parent.join(format!("{} ({}){}", stem, n, ext)))
+            .find(|path| !paths.contains(path) && fs::symlink_metadata(path).is_err())
+            .expect("there is always a free name");
+        paths.insert(path.clone());
+        path
+    }
+}
+
+fn split_extension(path: &Path) -> (String, String) {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match name.rfind('.') {
+        Some(i) if i > 0 => (name[..i].to_string(), name[i..].to_string()),
+        _ => (name, String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Fields {
+        Fields {
+            stem: "F0M000112233445566".into(),
+            ext: "flac".into(),
+            mid: Some("AaBbCcDdEeFfGg".into()),
+            title: Some("What? / Why: \"Now\"".into()),
+            artist: Some("AC/DC".into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let template = "{artist}/{title}.{ext}".parse::<NameTemplate>().unwrap();
+        assert_eq!(
+            template.render(&fields()),
+            Some(PathBuf::from("AC_DC/What_ _ Why_ _Now_.flac"))
+        );
+        let template = "{mid} - {stem}".parse::<NameTemplate>().unwrap();
+        assert_eq!(
+            template.render(&fields()),
+            Some(PathBuf::from("AaBbCcDdEeFfGg - F0M000112233445566.flac"))
+        );
+        let template = "{album}/{title}".parse::<NameTemplate>().unwrap();
+        assert_eq!(template.render(&fields()), None);
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert!("{title".parse::<NameTemplate>().is_err());
+        assert!("{year}".parse::<NameTemplate>().is_err());
+        assert!("/{title}".parse::<NameTemplate>().is_err());
+        assert!("../{title}".parse::<NameTemplate>().is_err());
+        assert!("{artist}//{title}".parse::<NameTemplate>().is_err());
+    }
+
+    #[test]
+    fn test_clean_component() {
+        assert_eq!(clean_component(" .. ", true), "_");
+        assert_eq!(clean_component("name. ", false), "name");
+        let long = format!("{}.flac", "あ".repeat(100));
+        let cleaned = clean_component(&long, true);
+        assert!(cleaned.len() <= MAX_COMPONENT_LEN);
+        assert!(cleaned.ends_with("あ.flac"));
+    }
+
+    #[test]
+    fn test_claims() {
+        let claims = Claims::default();
+        let path = PathBuf::from("out/a.flac");
+        assert_eq!(claims.claim(path.clone()), (path.clone(), false));
+        assert_eq!(
+            claims.claim(path.clone()).0,
+            PathBuf::from("out/a (2).flac")
+        );
+        assert_eq!(claims.claim(path).0, PathBuf::from("out/a (3).flac"));
+    }
+
+    #[test]
+    fn test_claim_free() {
+        let dir = tempfile::tempdir().unwrap();
+        let claims = Claims::default();
+        let path = dir.path().join("a.flac");
+        fs::write(&path, b"").unwrap();
+        fs::write(dir.path().join("a (2).flac"), b"").unwrap();
+        claims.claim(dir.path().join("a (3).flac"));
+        assert_eq!(claims.claim_free(&path), dir.path().join("a (4).flac"));
+        assert_eq!(claims.claim_free(&path), dir.path().join("a (5).flac"));
+    }
+}
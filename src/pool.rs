@@ -0,0 +1,212 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::term;
+use anyhow::Result;
+use std::io::{stderr, stdout, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+/// Messages of a single task, printed together once the task finishes.
+#[derive(Default)]
+pub struct Log {
+    lines: Vec<(Level, String)>,
+}
+
+#[derive(Clone, Copy)]
+enum Level {
+    Out,
+    Warn,
+    Err,
+}
+
+impl Log {
+    pub fn out<T: Into<String>>(&mut self, message: T) {
+        self.lines.push((Level::Out, message.into()));
+    }
+
+    /// A warning, printed by [`term::warn`] so that `--quiet` silences it.
+    pub fn warn<T: Into<String>>(&mut self, message: T) {
+        self.lines.push((Level::Warn, message.into()));
+    }
+
+    pub fn err<T: Into<String>>(&mut self, message: T) {
+        self.lines.push((Level::Err, message.into()));
+    }
+
+    /// Add the lines of `other`, e.g. kept from an earlier step of the same task.
+    pub fn extend(&mut self, other: &Log) {
+        self.lines.extend(other.lines.iter().cloned());
+    }
+
+    /// Print every line to the standard error, for when the standard output carries data.
+    pub fn flush_to_stderr(self) {
+        let mut err = stderr().lock();
+        for (level, line) in self.lines {
+            match level {
+                Level::Warn => term::warn(line),
+                _ => {
+                    let _ = writeln!(err, "{}", line);
+                }
+            }
+        }
+    }
+
+    pub fn flush(self) {
+        let mut out = stdout().lock();
+        let mut err = stderr().lock();
+        for (level, line) in self.lines {
+            match level {
+                Level::Out => {
+                    let _ = writeln!(out, "{}", line);
+                }
+                Level::Warn => term::warn(line),
+                Level::Err => {
+                    let _ = writeln!(err, "{}", line);
+                }
+            }
+        }
+    }
+}
+
+/// Global limit on the memory used by decryption buffers.
+pub struct Budget {
+    limit: usize,
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A reservation from a [`Budget`], returned when dropped.
+pub struct Permit<'a> {
+    budget: &'a Budget,
+    size: usize,
+}
+
+impl Budget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit: limit.max(1),
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Block until `size` bytes are available. Requests larger than the limit are clamped to it.
+    pub fn acquire(&self, size: usize) -> Permit<'_> {
+        let size = size.clamp(1, self.limit);
+        let mut used = self.used.lock().unwrap();
+        while *used + size > self.limit {
+            used = self.freed.wait(used).unwrap();
+        }
+        *used += size;
+        Permit { budget: self, size }
+    }
+}
+
+impl Permit<'_> {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.budget.used.lock().unwrap() -= self.size;
+        self.budget.freed.notify_all();
+    }
+}
+
+/// Run `f` over `tasks` on `jobs` worker threads and collect the results in task order.
+///
+/// With `fail_fast`, no new task is started once one has failed; those tasks are left `None`.
+pub fn run<T, F>(jobs: usize, tasks: &[T], fail_fast: bool, f: F) -> Vec<Option<Result<()>>>
+where
+    T: Sync,
+    F: Fn(&T, &mut Log) -> Result<()> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results = Mutex::new((0..tasks.len()).map(|_| None).collect::<Vec<_>>());
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, tasks.len().max(1)) {
+            scope.spawn(|| loop {
+                if fail_fast && failed.load(Ordering::Relaxed) {
+                    break;
+                }
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= tasks.len() {
+                    break;
+                }
+                let mut log = Log::default();
+                let result = f(&tasks[i], &mut log);
+                log.flush();
+                if result.is_err() {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results.into_inner().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+
+    #[test]
+    fn test_results_in_task_order() {
+        let tasks = (0..32).collect::<Vec<_>>();
+        let results = run(4, &tasks, false, |&i, _| match i % 3 {
+            0 => bail!("{}", i),
+            _ => Ok(()),
+        });
+        assert_eq!(results.len(), tasks.len());
+        for (i, result) in results.iter().enumerate() {
+            assert_eq!(result.as_ref().unwrap().is_err(), i % 3 == 0);
+        }
+    }
+
+    #[test]
+    fn test_fail_fast() {
+        let tasks = (0..32).collect::<Vec<_>>();
+        let results = run(1, &tasks, true, |&i, _| match i {
+            3 => bail!("{}", i),
+            _ => Ok(()),
+        });
+        assert!(results[..3]
+            .iter()
+            .all(|result| matches!(result, Some(Ok(())))));
+        assert!(matches!(results[3], Some(Err(_))));
+        assert!(results[4..].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_budget_is_never_exceeded() {
+        let budget = Budget::new(10);
+        let peak = Mutex::new(0usize);
+        let tasks = (1..=20).collect::<Vec<_>>();
+        run(8, &tasks, false, |&size, _| {
+            let permit = budget.acquire(size);
+            let used = *budget.used.lock().unwrap();
+            let mut peak = peak.lock().unwrap();
+            *peak = (*peak).max(used);
+            assert!(permit.size() <= 10);
+            Ok(())
+        });
+        assert!(peak.into_inner().unwrap() <= 10);
+        assert_eq!(*budget.used.lock().unwrap(), 0);
+    }
+}
@@ -0,0 +1,211 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::job::JobError;
+use crate::keys::KeyError;
+use crate::name::Resolution;
+use crate::tag::TagError;
+use crate::verify::Verdict;
+use anyhow::Result;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use umc_qmc::footer::FooterParseError;
+use umc_qmc::QmcCryptoError;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Decrypted,
+    Copied,
+    #[default]
+    Skipped,
+    /// Done by a previous run and unchanged since, see `--resume`.
+    Unchanged,
+    Failed,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Decrypted => "decrypted",
+            Status::Copied => "copied",
+            Status::Skipped => "skipped",
+            Status::Unchanged => "unchanged",
+            Status::Failed => "failed",
+        }
+    }
+}
+
+/// Outcome of a single file.
+#[derive(Serialize, Debug, Default)]
+pub struct Record {
+    pub input: PathBuf,
+    pub output: Option<PathBuf>,
+    pub status: Status,
+    pub error_kind: Option<&'static str>,
+    pub error: Option<String>,
+    pub footer: Option<&'static str>,
+    /// Metadata was written into the output, see `--tag`.
+    pub tagged: bool,
+    /// Result of `--verify`, `None` when the output was not checked.
+    pub verification: Option<Verdict>,
+    pub verification_detail: Option<String>,
+    /// What was done about an output which already existed, see `--on-conflict`.
+    pub conflict: Option<Resolution>,
+    pub bytes_written: u64,
+    pub elapsed_ms: u64,
+}
+
+/// Totals of a run, written as the last line of the report.
+#[derive(Serialize, Debug, Default)]
+pub struct Summary {
+    pub total: usize,
+    pub decrypted: usize,
+    pub copied: usize,
+    pub skipped: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+    /// Files known to the database but not found in the input directory.
+    pub missing: usize,
+    pub verified: usize,
+    pub suspicious: usize,
+    pub broken: usize,
+    pub bytes_written: u64,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Line<'a> {
+    File(&'a Record),
+    Summary(&'a Summary),
+}
+
+impl Record {
+    pub fn new(input: &Path) -> Self {
+        Self {
+            input: input.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    pub fn fail(&mut self, err: &anyhow::Error) {
+        self.status = Status::Failed;
+        self.error_kind = Some(error_kind(err));
+        self.error = Some(err.to_string());
+    }
+}
+
+impl Summary {
+    pub fn add(&mut self, record: &Record) {
+        self.total += 1;
+        self.bytes_written += record.bytes_written;
+        match record.status {
+            Status::Decrypted => self.decrypted += 1,
+            Status::Copied => self.copied += 1,
+            Status::Skipped => self.skipped += 1,
+            Status::Unchanged => self.unchanged += 1,
+            Status::Failed => self.failed += 1,
+        }
+        match record.verification {
+            Some(Verdict::Verified) => self.verified += 1,
+            Some(Verdict::Suspicious) => self.suspicious += 1,
+            Some(Verdict::Broken) => self.broken += 1,
+            None => {}
+        }
+    }
+}
+
+/// Classify an error for machine consumption.
+pub fn error_kind(err: &anyhow::Error) -> &'static str {
+    if let Some(err) = err.downcast_ref::<JobError>() {
+        return err.kind();
+    }
+    if err.downcast_ref::<KeyError>().is_some() {
+        return "ambiguous_key";
+    }
+    if err.downcast_ref::<TagError>().is_some() {
+        return "tag";
+    }
+    if err.downcast_ref::<FooterParseError>().is_some() {
+        return "footer";
+    }
+    if err.downcast_ref::<QmcCryptoError>().is_some() {
+        return "cipher";
+    }
+    if err.downcast_ref::<std::io::Error>().is_some() {
+        return "io";
+    }
+    "other"
+}
+
+/// NDJSON report, one line per file followed by the summary.
+pub struct Reporter {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl Reporter {
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self {
+            writer: Mutex::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    pub fn record(&self, record: &Record) -> Result<()> {
+        self.write(&Line::File(record))
+    }
+
+    pub fn summary(&self, summary: &Summary) -> Result<()> {
+        self.write(&Line::Summary(summary))
+    }
+
+    fn write(&self, line: &Line) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        serde_json::to_writer(&mut *writer, line)?;
+        writeln!(writer)?;
+        // keep the report usable when the run is interrupted
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_format() {
+        let mut record = Record::new(Path::new("a.mflac"));
+        record.fail(&JobError::KeyNotFound("a.mflac".into()).into());
+        let line = serde_json::to_value(Line::File(&record)).unwrap();
+        assert_eq!(line["type"], "file");
+        assert_eq!(line["status"], "failed");
+        assert_eq!(line["error_kind"], "missing_key");
+
+        let mut summary = Summary::default();
+        summary.add(&record);
+        let line = serde_json::to_value(Line::Summary(&summary)).unwrap();
+        assert_eq!(line["type"], "summary");
+        assert_eq!(line["failed"], 1);
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let err = anyhow::Error::from(std::io::Error::other("boom"));
+        assert_eq!(error_kind(&err), "io");
+        assert_eq!(error_kind(&anyhow::anyhow!("boom")), "other");
+    }
+}
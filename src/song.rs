@@ -0,0 +1,492 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::utils::{common_suffix, components, normalize};
+use anyhow::Result;
+use rusqlite::types::Value;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Unit of a duration column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Unit {
+    Seconds,
+    Milliseconds,
+}
+
+/// A song table of the player database, and the columns each field is read from, matched
+/// case-insensitively. Columns missing from the table are left empty, and reported by
+/// [`SongIndex::warnings`].
+struct SongTable {
+    name: &'static str,
+    path: &'static [&'static str],
+    media_mid: &'static [&'static str],
+    mid: &'static [&'static str],
+    title: &'static [&'static str],
+    artist: &'static [&'static str],
+    album: &'static [&'static str],
+    duration: &'static [(&'static str, Unit)],
+}
+
+/// Targets the `Song_table` of `player_process_db`, the database of the QQ Music Android app
+/// which also holds `audio_file_ekey_table`. Other versions of the app may name the table or its
+/// columns differently: a database without them only gives keys, and a warning.
+// noinspection SpellCheckingInspection
+const SONG_TABLES: &[SongTable] = &[SongTable {
+    name: "Song_table",
+    path: &["file_path"],
+    media_mid: &["file_mid", "media_mid"],
+    mid: &["mid"],
+    title: &["name"],
+    artist: &["singername"],
+    album: &["albumname"],
+    duration: &[
+        ("interval", Unit::Seconds),
+        ("duration", Unit::Milliseconds),
+    ],
+}];
+
+/// A song of the player database.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Song {
+    /// Path of the audio file on the device.
+    pub file_path: Option<String>,
+    pub media_mid: Option<String>,
+    pub mid: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// Duration in seconds.
+    pub duration: Option<u64>,
+}
+
+/// Songs of the player database, looked up by the path or the ids of an audio file.
+///
+/// Songs are read from the tables of [`SONG_TABLES`]. File paths of the ekey table are kept as
+/// well, so that [`SongIndex::missing`] covers every file the database knows about.
+#[derive(Debug, Default)]
+pub struct SongIndex {
+    songs: Vec<Song>,
+    by_path: HashMap<String, usize>,
+    by_media_mid: HashMap<String, usize>,
+    by_mid: HashMap<String, usize>,
+    /// Songs by the bare file name of their path, which several songs may share.
+    by_filename: HashMap<String, Vec<usize>>,
+    /// Every path in the database, by bare file name.
+    filenames: HashMap<String, Vec<String>>,
+    /// Song tables or columns which were not found, see [`SONG_TABLES`].
+    warnings: Vec<String>,
+}
+
+/// Columns of a [`SongTable`] found in the database.
+struct Columns {
+    path: Option<String>,
+    media_mid: Option<String>,
+    mid: Option<String>,
+    title: String,
+    artist: Option<String>,
+    album: Option<String>,
+    duration: Option<(String, Unit)>,
+}
+
+impl SongIndex {
+    /// Add the songs of a player database. Songs of databases added first take precedence.
+    pub fn add_database(&mut self, path: &Path) -> Result<()> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        self.add_connection(&conn, path)
+    }
+
+    #[cfg(test)]
+    fn from_connection(conn: &Connection) -> Result<Self> {
+        let mut index = Self::default();
+        index.add_connection(conn, Path::new(":memory:"))?;
+        Ok(index)
+    }
+
+    fn add_connection(&mut self, conn: &Connection, path: &Path) -> Result<()> {
+        if table_exists(conn, "audio_file_ekey_table")? {
+            self.add_paths(conn, "audio_file_ekey_table", "file_path")?;
+        }
+        for table in SONG_TABLES {
+            if !table_exists(conn, table.name)? {
+                self.warnings.push(format!(
+                    "{:?}: table {} not found, song metadata is not available",
+                    path, table.name
+                ));
+                continue;
+            }
+            let columns = conn
+                .prepare(&format!("PRAGMA table_info({})", quote(table.name)))?
+                .query_map([], |row| row.get::<_, String>(1))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let (found, missing) = Columns::find(table, &columns);
+            if !missing.is_empty() {
+                self.warnings.push(format!(
+                    "{:?}: {} lacks the column(s) {}",
+                    path,
+                    table.name,
+                    missing.join(", ")
+                ));
+            }
+            if let Some(columns) = found {
+                self.add_songs(conn, table.name, &columns)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Song tables and columns the databases did not have, so that songs may be missing.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Find the song of the audio file at `path`, whose footer may give its `media_mid` and
+    /// song `mid`: by full path, media mid, song mid, then file name.
+    ///
+    /// Songs sharing the file name are ranked by the trailing path components they share with
+    /// the input; when the best ones are different songs, the match is ambiguous and no song
+    /// is returned.
+    pub fn get(
+        &self,
+        path: &Path,
+        filename: &str,
+        media_mid: Option<&str>,
+        mid: Option<&str>,
+    ) -> Option<&Song> {
+        let path = path.to_string_lossy();
+        let found = self
+            .by_path
+            .get(&normalize(&path))
+            .or_else(|| media_mid.and_then(|media_mid| self.by_media_mid.get(media_mid)))
+            .or_else(|| mid.and_then(|mid| self.by_mid.get(mid)));
+        if let Some(&i) = found {
+            return Some(&self.songs[i]);
+        }
+        let candidates = self.by_filename.get(filename)?;
+        let input = components(&path);
+        let suffix = |i: usize| {
+            let file_path = self.songs[i].file_path.as_deref().unwrap_or_default();
+            common_suffix(&components(file_path), &input)
+        };
+        let best = candidates.iter().map(|&i| suffix(i)).max()?;
+        let mut top = candidates
+            .iter()
+            .filter(|&&i| suffix(i) == best)
+            .map(|&i| &self.songs[i]);
+        let first = top.next()?;
+        top.all(|song| song.same_song(first)).then_some(first)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Song> {
+        self.songs.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.songs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.songs.is_empty()
+    }
+
+    /// Paths in the database whose file name is not in `present`, sorted.
+    pub fn missing(&self, present: &HashSet<String>) -> Vec<&str> {
+        let mut missing = self
+            .filenames
+            .iter()
+            .filter(|(filename, _)| !present.contains(*filename))
+            .flat_map(|(_, paths)| paths.iter().map(String::as_str))
+            .collect::<Vec<_>>();
+        missing.sort_unstable();
+        missing
+    }
+
+    fn add_paths(&mut self, conn: &Connection, table: &str, column: &str) -> Result<()> {
+        let sql = format!("SELECT {} FROM {}", quote(column), quote(table));
+        let mut stmt = conn.prepare(&sql)?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            if let Some(path) = text(row.get(0)?) {
+                self.add_path(&path);
+            }
+        }
+        Ok(())
+    }
+
+    fn add_path(&mut self, path: &str) -> Option<String> {
+        let filename = filename(path)?;
+        let paths = self.filenames.entry(filename.clone()).or_default();
+        if !paths.iter().any(|known| known == path) {
+            paths.push(path.to_string());
+        }
+        Some(filename)
+    }
+
+    fn add_songs(&mut self, conn: &Connection, table: &str, columns: &Columns) -> Result<()> {
+        let select = [
+            &columns.path,
+            &columns.media_mid,
+            &columns.mid,
+            &Some(columns.title.clone()),
+            &columns.artist,
+            &columns.album,
+            &columns.duration.as_ref().map(|(column, _)| column.clone()),
+        ]
+        .map(|column| column.as_deref().map_or("NULL".into(), quote))
+        .join(", ");
+        let unit = columns
+            .duration
+            .as_ref()
+            .map_or(Unit::Seconds, |&(_, unit)| unit);
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM {}", select, quote(table)))?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let song = Song {
+                file_path: text(row.get(0)?),
+                media_mid: text(row.get(1)?),
+                mid: text(row.get(2)?),
+                title: text(row.get(3)?),
+                artist: text(row.get(4)?),
+                album: text(row.get(5)?),
+                duration: duration(row.get(6)?, unit),
+            };
+            if song.title.is_none() {
+                continue;
+            }
+            let i = self.songs.len();
+            if let Some(path) = &song.file_path {
+                if let Some(filename) = self.add_path(path) {
+                    self.by_filename.entry(filename).or_default().push(i);
+                }
+                self.by_path.entry(normalize(path)).or_insert(i);
+            }
+            if let Some(media_mid) = &song.media_mid {
+                self.by_media_mid.entry(media_mid.clone()).or_insert(i);
+            }
+            if let Some(mid) = &song.mid {
+                self.by_mid.entry(mid.clone()).or_insert(i);
+            }
+            self.songs.push(song);
+        }
+        Ok(())
+    }
+}
+
+impl Song {
+    /// Whether `other` describes the same song, wherever its file is.
+    fn same_song(&self, other: &Song) -> bool {
+        (
+            &self.mid,
+            &self.media_mid,
+            &self.title,
+            &self.artist,
+            &self.album,
+        ) == (
+            &other.mid,
+            &other.media_mid,
+            &other.title,
+            &other.artist,
+            &other.album,
+        )
+    }
+}
+
+impl Columns {
+    /// The columns of `table` among the `columns` of the database, `None` without a title,
+    /// along with the expected columns which are missing.
+    fn find(table: &SongTable, columns: &[String]) -> (Option<Self>, Vec<&'static str>) {
+        let column = |name: &str| {
+            columns
+                .iter()
+                .find(|column| column.eq_ignore_ascii_case(name))
+                .cloned()
+        };
+        let mut missing = Vec::new();
+        let mut find = |candidates: &[&'static str]| {
+            let found = candidates.iter().find_map(|candidate| column(candidate));
+            if found.is_none() {
+                missing.extend(candidates.first());
+            }
+            found
+        };
+        let path = find(table.path);
+        let media_mid = find(table.media_mid);
+        let mid = find(table.mid);
+        let title = find(table.title);
+        let artist = find(table.artist);
+        let album = find(table.album);
+        let duration = table
+            .duration
+            .iter()
+            .find_map(|&(name, unit)| column(name).map(|column| (column, unit)));
+        if duration.is_none() {
+            missing.extend(table.duration.first().map(|&(name, _)| name));
+        }
+        let columns = title.map(|title| Self {
+            path,
+            media_mid,
+            mid,
+            title,
+            artist,
+            album,
+            duration,
+        });
+        (columns, missing)
+    }
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    let found = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1 COLLATE NOCASE",
+            [table],
+            |_| Ok(()),
+        )
+        .optional()?;
+    Ok(found.is_some())
+}
+
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// Bare file name of a device path, which may use either separator.
+fn filename(path: &str) -> Option<String> {
+    path.rsplit(['/', '\\'])
+        .next()
+        .filter(|name| !name.is_empty())
+        .map(Into::into)
+}
+
+fn text(value: Value) -> Option<String> {
+    match value {
+        Value::Text(text) => Some(text.trim().to_string()).filter(|text| !text.is_empty()),
+        Value::Integer(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// A duration in `unit`, in seconds.
+fn duration(value: Value, unit: Unit) -> Option<u64> {
+    let n = match value {
+        Value::Integer(n) => n,
+        Value::Real(n) => n as i64,
+        Value::Text(text) => text.trim().parse().ok()?,
+        _ => return None,
+    };
+    match (n, unit) {
+        (n, _) if n <= 0 => None,
+        (n, Unit::Seconds) => Some(n as u64),
+        (n, Unit::Milliseconds) => Some(n as u64 / 1000),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The song of the file at `path`, whose footer gives `media_mid`.
+    fn get<'a>(index: &'a SongIndex, path: &str, media_mid: Option<&str>) -> Option<&'a Song> {
+        let path = Path::new(path);
+        let filename = path.file_name().unwrap().to_str().unwrap();
+        index.get(path, filename, media_mid, None)
+    }
+
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE audio_file_ekey_table (file_path TEXT, ekey TEXT);
+             INSERT INTO audio_file_ekey_table VALUES
+                 ('/storage/emulated/0/qqmusic/song/a.mflac', 'k1'),
+                 ('/storage/emulated/0/qqmusic/song/gone.mgg', 'k2');
+             CREATE TABLE Song_table (
+                 id INTEGER, Name TEXT, singername TEXT, albumname TEXT,
+                 interval INTEGER, file_mid TEXT, file_path TEXT
+             );
+             INSERT INTO Song_table VALUES
+                 (1, 'Song A', 'Artist', 'Album', 215, '001y7CaR29k6YP',
+                  '/storage/emulated/0/qqmusic/song/a.mflac'),
+                 (2, 'Song B', 'Artist', NULL, 180, '002aaaaaaaaaaa', NULL),
+                 (3, NULL, NULL, NULL, NULL, NULL, '/x/untitled.mgg');
+             CREATE TABLE unrelated (name TEXT);",
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_lookup() {
+        let index = SongIndex::from_connection(&database()).unwrap();
+        assert_eq!(index.len(), 2);
+        let song = get(&index, "/music/a.mflac", None).unwrap();
+        assert_eq!(song.title.as_deref(), Some("Song A"));
+        assert_eq!(song.album.as_deref(), Some("Album"));
+        assert_eq!(song.duration, Some(215));
+        let song = get(&index, "b.mgg", Some("002aaaaaaaaaaa")).unwrap();
+        assert_eq!(song.title.as_deref(), Some("Song B"));
+        assert_eq!(song.duration, Some(180));
+        assert!(get(&index, "c.mgg", Some("003")).is_none());
+        assert_eq!(
+            index.warnings(),
+            ["\":memory:\": Song_table lacks the column(s) mid"]
+        );
+    }
+
+    #[test]
+    fn test_no_song_table() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE audio_file_ekey_table (file_path TEXT, ekey TEXT);
+             INSERT INTO audio_file_ekey_table VALUES ('/sdcard/a.mflac', 'k1');",
+        )
+        .unwrap();
+        let index = SongIndex::from_connection(&conn).unwrap();
+        assert_eq!(index.len(), 0);
+        assert_eq!(index.missing(&HashSet::new()), ["/sdcard/a.mflac"]);
+        assert_eq!(
+            index.warnings(),
+            ["\":memory:\": table Song_table not found, song metadata is not available"]
+        );
+    }
+
+    #[test]
+    fn test_same_filename() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE Song_table (name TEXT, duration INTEGER, file_path TEXT);
+             INSERT INTO Song_table VALUES
+                 ('Song A', 90000, '/sdcard/qqmusic/song/a.mflac'),
+                 ('Other A', 90000, '/sdcard/qqmusic/other/a.mflac');",
+        )
+        .unwrap();
+        let index = SongIndex::from_connection(&conn).unwrap();
+        let song = get(&index, "/in/other/a.mflac", None).unwrap();
+        assert_eq!(song.title.as_deref(), Some("Other A"));
+        // milliseconds, by the name of the column
+        assert_eq!(song.duration, Some(90));
+        // as close to both, ambiguous
+        assert!(get(&index, "/in/a.mflac", None).is_none());
+        assert_eq!(index.missing(&HashSet::new()).len(), 2);
+    }
+
+    #[test]
+    fn test_missing() {
+        let index = SongIndex::from_connection(&database()).unwrap();
+        let present = HashSet::from(["a.mflac".to_string()]);
+        assert_eq!(
+            index.missing(&present),
+            vec!["/storage/emulated/0/qqmusic/song/gone.mgg"]
+        );
+    }
+}
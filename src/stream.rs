@@ -0,0 +1,102 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::term;
+use anyhow::{bail, Result};
+use std::io::{ErrorKind, Read, Write};
+use umc_qmc::footer::{self, FooterParseError};
+use umc_qmc::QMCv2Cipher;
+
+/// Size of the reads from the input.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Decrypt a QMCv2 stream which cannot be seeked, e.g. the standard input, into `writer`.
+///
+/// The footer is only known at the end of the stream, so the last
+/// [`footer::INITIAL_DETECTION_LEN`] bytes read are held back: once the input ends, the footer
+/// is looked for in them, as [`crate::utils::read_footer`] does for files, and trimmed off.
+/// Returns the number of bytes written.
+pub fn decrypt<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    cipher: &QMCv2Cipher,
+) -> Result<u64> {
+    let mut pending = Vec::with_capacity(CHUNK_SIZE + footer::INITIAL_DETECTION_LEN);
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let n = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => Err(err)?,
+        };
+        pending.extend_from_slice(&chunk[..n]);
+        if pending.len() > footer::INITIAL_DETECTION_LEN {
+            let ready = pending.len() - footer::INITIAL_DETECTION_LEN;
+            cipher.decrypt(&mut pending[..ready], offset);
+            writer.write_all(&pending[..ready])?;
+            pending.drain(..ready);
+            offset += ready;
+        }
+    }
+    let footer_size = match footer::from_byte_slice(&pending) {
+        Ok(metadata) => metadata.map_or(0, |metadata| metadata.size),
+        // too short for any footer
+        Err(FooterParseError::BufferTooSmall(_)) if pending.len() < 8 => 0,
+        Err(err) => {
+            term::warn(format!("failed to parse qmc metadata: {}", err));
+            0
+        }
+    };
+    if footer_size > pending.len() {
+        bail!("the footer is larger than the input");
+    }
+    let rest = pending.len() - footer_size;
+    cipher.decrypt(&mut pending[..rest], offset);
+    writer.write_all(&pending[..rest])?;
+    writer.flush()?;
+    Ok((offset + rest) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use umc_qmc::footer::android_stag::STagMetadata;
+    use umc_qmc::footer::{Data, Metadata};
+
+    #[test]
+    fn test_decrypt() {
+        let key = (0..200).map(|i| (i * 31 + 7) as u8).collect::<Vec<_>>();
+        let cipher = QMCv2Cipher::new(&key).unwrap();
+        for len in [0, 100, footer::INITIAL_DETECTION_LEN, 3 * CHUNK_SIZE + 17] {
+            let plain = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let mut encrypted = plain.clone();
+            cipher.encrypt(&mut encrypted, 0);
+            let metadata = Metadata {
+                size: 0,
+                ekey: None,
+                data: Data::AndroidSTag(STagMetadata {
+                    media_mid: "0011wjLv1bIZ8j".to_string(),
+                    resource_id: 12345,
+                }),
+            };
+            encrypted.extend(metadata.to_bytes().unwrap());
+
+            let mut output = Vec::new();
+            let n = decrypt(&mut encrypted.as_slice(), &mut output, &cipher).unwrap();
+            assert_eq!(n, len as u64);
+            assert_eq!(output, plain);
+        }
+    }
+}
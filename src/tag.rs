@@ -0,0 +1,401 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::utils;
+use anyhow::Result;
+use byteorder::{ByteOrder, ReadBytesExt, BE};
+use lofty::config::{ParseOptions, WriteOptions};
+use lofty::flac::FlacFile;
+use lofty::id3::v2::Id3v2Tag;
+use lofty::mp4::{Atom, AtomData, AtomIdent, Ilst, Mp4File};
+use lofty::mpeg::MpegFile;
+use lofty::ogg::{OpusFile, VorbisComments, VorbisFile};
+use lofty::prelude::*;
+use md5::{Digest, Md5};
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom};
+use thiserror::Error;
+use umc_qmc::audio::AudioType;
+
+/// Names of the custom fields holding the ids of the song, as Vorbis comments, ID3v2 `TXXX`
+/// descriptions and iTunes freeform atoms.
+const MID_FIELD: &str = "QQMUSIC_MID";
+const MEDIA_MID_FIELD: &str = "QQMUSIC_MEDIA_MID";
+const ITUNES_MEAN: &str = "com.apple.iTunes";
+
+/// Values written by the `--tag` stage, `None` when unknown.
+#[derive(Debug, Default, Clone)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub mid: Option<String>,
+    pub media_mid: Option<String>,
+}
+
+impl Tags {
+    pub fn is_empty(&self) -> bool {
+        [
+            &self.title,
+            &self.artist,
+            &self.album,
+            &self.mid,
+            &self.media_mid,
+        ]
+        .iter()
+        .all(|value| value.is_none())
+    }
+}
+
+/// Result of [`write`] which left the file untouched.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Skipped {
+    /// No tag support for this audio type.
+    Unsupported,
+    /// The container could not be parsed.
+    Unreadable(String),
+}
+
+#[derive(Error, Debug)]
+pub enum TagError {
+    #[error("writing tags changed the audio data")]
+    AudioChanged,
+}
+
+/// Add `tags` to the decrypted `file`, keeping the values it already has.
+///
+/// Only the metadata is rewritten (FLAC metadata blocks, Ogg comment header pages, the ID3v2 tag
+/// in front of MP3 frames and the `moov.udta` atoms of MP4), the audio data is copied as is.
+/// This is checked by hashing the audio data before and after: the tags are written to a
+/// temporary copy, which only replaces the content of `file` when the audio data is unchanged.
+/// A [`TagError`] leaves `file` untouched, other errors mean that it must be discarded.
+pub fn write(file: &mut File, audio: AudioType, tags: &Tags) -> Result<Option<Skipped>> {
+    let before = match audio_digest(file, audio) {
+        Ok(Some(digest)) => digest,
+        Ok(None) => return Ok(Some(Skipped::Unsupported)),
+        Err(err) => return Ok(Some(Skipped::Unreadable(err.to_string()))),
+    };
+    let mut staged = tempfile::tempfile()?;
+    file.seek(SeekFrom::Start(0))?;
+    io::copy(file, &mut staged)?;
+    let skipped = match audio {
+        AudioType::Flac => update(&mut staged, |flac: &mut FlacFile| {
+            let mut comments = flac.vorbis_comments().cloned().unwrap_or_default();
+            fill_vorbis_comments(&mut comments, tags);
+            flac.set_vorbis_comments(comments);
+        })?,
+        AudioType::OggVorbis => update(&mut staged, |ogg: &mut VorbisFile| {
+            fill_vorbis_comments(ogg.vorbis_comments_mut(), tags)
+        })?,
+        AudioType::OggOpus => update(&mut staged, |ogg: &mut OpusFile| {
+            fill_vorbis_comments(ogg.vorbis_comments_mut(), tags)
+        })?,
+        AudioType::Mp3 => update(&mut staged, |mpeg: &mut MpegFile| {
+            let mut id3v2 = mpeg.id3v2().cloned().unwrap_or_else(Id3v2Tag::new);
+            fill_id3v2(&mut id3v2, tags);
+            mpeg.set_id3v2(id3v2);
+        })?,
+        AudioType::M4a => update(&mut staged, |mp4: &mut Mp4File| {
+            let mut ilst = mp4.ilst().cloned().unwrap_or_default();
+            fill_ilst(&mut ilst, tags);
+            mp4.set_ilst(ilst);
+        })?,
+        _ => Some(Skipped::Unsupported),
+    };
+    if skipped.is_some() {
+        return Ok(skipped);
+    }
+    if audio_digest(&mut staged, audio)? != Some(before) {
+        Err(TagError::AudioChanged)?;
+    }
+    staged.seek(SeekFrom::Start(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    file.set_len(0)?;
+    io::copy(&mut staged, file)?;
+    Ok(None)
+}
+
+/// Parse `file` as `T`, `edit` it and write it back.
+fn update<T: AudioFile>(file: &mut File, edit: impl FnOnce(&mut T)) -> Result<Option<Skipped>> {
+    file.seek(SeekFrom::Start(0))?;
+    let options = ParseOptions::new().read_properties(false);
+    let mut audio_file = match T::read_from(file, options) {
+        Ok(audio_file) => audio_file,
+        Err(err) => return Ok(Some(Skipped::Unreadable(err.to_string()))),
+    };
+    edit(&mut audio_file);
+    file.seek(SeekFrom::Start(0))?;
+    audio_file.save_to(file, WriteOptions::default())?;
+    Ok(None)
+}
+
+/// MD5 of the audio data of `file`, i.e. everything but the metadata, or `None` for audio
+/// types without tag support.
+fn audio_digest(file: &mut File, audio: AudioType) -> Result<Option<[u8; 16]>> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(&mut *file);
+    let mut hasher = Md5::new();
+    match audio {
+        AudioType::Flac => {
+            utils::skip_id3v2(&mut reader)?;
+            let mut magic = [0u8; 4];
+            reader.read_exact(&mut magic)?;
+            loop {
+                let mut header = [0u8; 4];
+                reader.read_exact(&mut header)?;
+                reader.seek_relative(BE::read_u24(&header[1..]) as i64)?;
+                if header[0] & 0x80 != 0 {
+                    break;
+                }
+            }
+            io::copy(&mut reader, &mut hasher)?;
+        }
+        AudioType::Mp3 => {
+            utils::skip_id3v2(&mut reader)?;
+            io::copy(&mut reader, &mut hasher)?;
+        }
+        AudioType::OggVorbis => digest_ogg_packets(&mut reader, 3, &mut hasher)?,
+        AudioType::OggOpus => digest_ogg_packets(&mut reader, 2, &mut hasher)?,
+        AudioType::M4a => loop {
+            let mut header = [0u8; 8];
+            match reader.read_exact(&mut header) {
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                result => result?,
+            }
+            let (size, header_size) = match BE::read_u32(&header) {
+                1 => (reader.read_u64::<BE>()?, 16),
+                // the atom extends to the end of the file
+                0 => (u64::MAX, 8),
+                size => (size as u64, 8),
+            };
+            let payload = size.saturating_sub(header_size);
+            match &header[4..] {
+                b"mdat" => {
+                    io::copy(&mut (&mut reader).take(payload), &mut hasher)?;
+                }
+                _ => {
+                    reader.seek_relative(payload as i64)?;
+                }
+            }
+        },
+        _ => return Ok(None),
+    }
+    Ok(Some(hasher.finalize().into()))
+}
+
+/// Hash the packets of an Ogg stream after its `headers` header packets.
+fn digest_ogg_packets<R: Read>(reader: &mut R, headers: usize, hasher: &mut Md5) -> Result<()> {
+    let mut packet = 0;
+    loop {
+        let mut header = [0u8; 27];
+        match reader.read_exact(&mut header) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            result => result?,
+        }
+        if &header[..4] != b"OggS" {
+            Err(io::Error::new(ErrorKind::InvalidData, "lost Ogg page sync"))?;
+        }
+        let mut lacing = vec![0u8; header[26] as usize];
+        reader.read_exact(&mut lacing)?;
+        for &len in &lacing {
+            let mut segment = vec![0u8; len as usize];
+            reader.read_exact(&mut segment)?;
+            if packet >= headers {
+                hasher.update(&segment);
+            }
+            if len < 255 {
+                packet += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Title, artist and album are set through [`Accessor`], mapping to the native field of each
+/// tag format.
+fn fill_common<T: Accessor>(tag: &mut T, tags: &Tags) {
+    if let (None, Some(title)) = (tag.title(), &tags.title) {
+        tag.set_title(title.clone());
+    }
+    if let (None, Some(artist)) = (tag.artist(), &tags.artist) {
+        tag.set_artist(artist.clone());
+    }
+    if let (None, Some(album)) = (tag.album(), &tags.album) {
+        tag.set_album(album.clone());
+    }
+}
+
+fn custom_fields(tags: &Tags) -> impl Iterator<Item = (&'static str, &String)> {
+    [(MID_FIELD, &tags.mid), (MEDIA_MID_FIELD, &tags.media_mid)]
+        .into_iter()
+        .filter_map(|(field, value)| value.as_ref().map(|value| (field, value)))
+}
+
+fn fill_vorbis_comments(comments: &mut VorbisComments, tags: &Tags) {
+    fill_common(comments, tags);
+    for (field, value) in custom_fields(tags) {
+        if comments.get(field).is_none() {
+            comments.insert(field.to_string(), value.clone());
+        }
+    }
+}
+
+fn fill_id3v2(id3v2: &mut Id3v2Tag, tags: &Tags) {
+    fill_common(id3v2, tags);
+    for (field, value) in custom_fields(tags) {
+        if id3v2.get_user_text(field).is_none() {
+            id3v2.insert_user_text(field.to_string(), value.clone());
+        }
+    }
+}
+
+fn fill_ilst(ilst: &mut Ilst, tags: &Tags) {
+    fill_common(ilst, tags);
+    for (field, value) in custom_fields(tags) {
+        let ident = AtomIdent::Freeform {
+            mean: ITUNES_MEAN.into(),
+            name: field.into(),
+        };
+        if ilst.get(&ident).is_none() {
+            ilst.insert(Atom::new(ident, AtomData::UTF8(value.clone())));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    /// `fLaC`, a STREAMINFO block for 16-bit stereo at 44.1 kHz and a PADDING block.
+    fn flac_header() -> Vec<u8> {
+        let mut header = b"fLaC\x00\x00\x00\x22".to_vec();
+        header.extend_from_slice(&[0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0]);
+        header.extend_from_slice(&[0x0A, 0xC4, 0x42, 0xF0, 0, 0, 0, 0]);
+        header.extend_from_slice(&[0u8; 16]);
+        header.extend_from_slice(&[0x81, 0x00, 0x00, 0x08]);
+        header.extend_from_slice(&[0u8; 8]);
+        header
+    }
+
+    #[test]
+    fn test_flac_keeps_audio() {
+        let frames = (0..0x1000u32).map(|i| (i * 31) as u8).collect::<Vec<_>>();
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&flac_header()).unwrap();
+        file.write_all(&frames).unwrap();
+        let tags = Tags {
+            title: Some("Title".into()),
+            media_mid: Some("001y7CaR29k6YP".into()),
+            ..Default::default()
+        };
+        assert_eq!(write(&mut file, AudioType::Flac, &tags).unwrap(), None);
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let flac = FlacFile::read_from(&mut file, ParseOptions::new()).unwrap();
+        let comments = flac.vorbis_comments().unwrap();
+        assert_eq!(comments.title().as_deref(), Some("Title"));
+        assert_eq!(comments.get(MEDIA_MID_FIELD), Some("001y7CaR29k6YP"));
+        assert_eq!(comments.get(MID_FIELD), None);
+
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut data).unwrap();
+        assert!(data.ends_with(&frames));
+
+        // values already present are kept
+        let tags = Tags {
+            title: Some("Other".into()),
+            ..Default::default()
+        };
+        write(&mut file, AudioType::Flac, &tags).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let flac = FlacFile::read_from(&mut file, ParseOptions::new()).unwrap();
+        assert_eq!(
+            flac.vorbis_comments().unwrap().title().as_deref(),
+            Some("Title")
+        );
+    }
+
+    #[test]
+    fn test_audio_is_never_changed() {
+        // STREAMINFO as the last block, which some writers mishandle
+        let mut header = flac_header();
+        header[4] = 0x80;
+        header.truncate(42);
+        let frames = [0xAAu8; 0x40];
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&header).unwrap();
+        file.write_all(&frames).unwrap();
+        let tags = Tags {
+            title: Some("Title".into()),
+            ..Default::default()
+        };
+        let err = write(&mut file, AudioType::Flac, &tags).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TagError>(),
+            Some(TagError::AudioChanged)
+        ));
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [&header[..], &frames].concat());
+    }
+
+    #[test]
+    fn test_ogg_digest_skips_headers() {
+        fn page(sequence: u8, packets: &[&[u8]]) -> Vec<u8> {
+            let mut page = b"OggS\x00\x00".to_vec();
+            page.extend_from_slice(&[0u8; 12]);
+            page.extend_from_slice(&[sequence, 0, 0, 0, 0, 0, 0, 0]);
+            page.push(packets.len() as u8);
+            page.extend(packets.iter().map(|packet| packet.len() as u8));
+            packets
+                .iter()
+                .for_each(|packet| page.extend_from_slice(packet));
+            page
+        }
+        let digest = |pages: &[Vec<u8>]| {
+            let mut hasher = Md5::new();
+            digest_ogg_packets(&mut pages.concat().as_slice(), 2, &mut hasher).unwrap();
+            <[u8; 16]>::from(hasher.finalize())
+        };
+        let short = [
+            page(0, &[b"OpusHead"]),
+            page(1, &[b"OpusTags"]),
+            page(2, &[b"audio"]),
+        ];
+        let long = [
+            page(0, &[b"OpusHead"]),
+            page(1, &[b"OpusTags with a title"]),
+            page(2, &[b"audio"]),
+        ];
+        assert_eq!(digest(&short), digest(&long));
+        let other = [page(0, &[b"OpusHead", b"OpusTags"]), page(1, &[b"audio!"])];
+        assert_ne!(digest(&short), digest(&other));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut file = tempfile::tempfile().unwrap();
+        assert_eq!(
+            write(&mut file, AudioType::Wav, &Tags::default()).unwrap(),
+            Some(Skipped::Unsupported)
+        );
+        file.write_all(b"not a flac file").unwrap();
+        assert!(matches!(
+            write(&mut file, AudioType::Flac, &Tags::default()),
+            Err(_) | Ok(Some(Skipped::Unreadable(_)))
+        ));
+    }
+}
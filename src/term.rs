@@ -0,0 +1,72 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use clap::ColorChoice;
+use std::env;
+use std::fmt::Display;
+use std::io::{stderr, stdout, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Apply `--quiet` and `--color` to the messages printed by [`warn`] and [`info`].
+///
+/// With `auto`, colors are used when both the standard output and error are terminals and
+/// `NO_COLOR` is not set.
+pub fn init(quiet: bool, color: ColorChoice) {
+    QUIET.store(quiet, Ordering::Relaxed);
+    let color = match color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            stdout().is_terminal() && stderr().is_terminal() && env::var_os("NO_COLOR").is_none()
+        }
+    };
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+/// ANSI colors used by [`paint`].
+#[derive(Debug, Clone, Copy)]
+pub enum Color {
+    Red = 31,
+    Green = 32,
+    Yellow = 33,
+}
+
+/// `text` in `color` when colors are enabled.
+pub fn paint(text: impl Display, color: Color) -> String {
+    match COLOR.load(Ordering::Relaxed) {
+        true => format!("\x1b[{}m{}\x1b[0m", color as u8, text),
+        false => text.to_string(),
+    }
+}
+
+/// Print a warning to the standard error, unless quiet.
+pub fn warn(message: impl Display) {
+    if !QUIET.load(Ordering::Relaxed) {
+        eprintln!("{} {}", paint("warning:", Color::Yellow), message);
+    }
+}
+
+/// Print a summary or progress message to the standard error, unless quiet.
+pub fn info(message: impl Display) {
+    if !QUIET.load(Ordering::Relaxed) {
+        eprintln!("{}", message);
+    }
+}
+
+/// Print an error to the standard error.
+pub fn error(message: impl Display) {
+    eprintln!("{}", paint(message, Color::Red));
+}
@@ -0,0 +1,183 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::atomic::AtomicFile;
+use crate::config::Settings;
+use crate::keys::{self, KeyFormat, KeyRecord};
+use crate::term;
+use crate::{KeySourceArgs, EXIT_SUCCESS};
+use anyhow::{bail, Result};
+use clap::{Args, Subcommand};
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+#[derive(Args)]
+pub struct KeysArgs {
+    #[command(subcommand)]
+    command: KeysCommand,
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// Write the ekeys of databases and key files to a key file
+    Export(ExportArgs),
+    /// Merge key files, e.g. exported on another machine, into a local key store
+    Import(ImportArgs),
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    #[command(flatten)]
+    sources: KeySourceArgs,
+
+    /// Output format [default: by the extension of the output, or json]
+    #[arg(short, long)]
+    format: Option<KeyFormat>,
+
+    /// Write to this file instead of the standard output
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Add the length of the decrypted key and the envelope (v1 or v2) of every ekey
+    #[arg(long, default_value_t = false)]
+    details: bool,
+}
+
+#[derive(Args)]
+struct ImportArgs {
+    /// The key store to merge into, created when missing; its format is given by its extension
+    #[arg(short, long, value_name = "FILE")]
+    store: PathBuf,
+
+    /// Replace the keys of the store which differ from the imported ones instead of keeping them
+    #[arg(long, default_value_t = false)]
+    overwrite: bool,
+
+    /// Key files to import
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+}
+
+impl KeysArgs {
+    /// Run the subcommand; `settings` loads the configuration, which only `export` uses.
+    pub fn run(self, settings: impl FnOnce() -> Result<Settings>) -> Result<i32> {
+        match self.command {
+            KeysCommand::Export(mut args) => {
+                args.sources.apply(&settings()?);
+                args.run()
+            }
+            KeysCommand::Import(args) => args.run(),
+        }
+    }
+}
+
+impl ExportArgs {
+    fn run(&self) -> Result<i32> {
+        if self.sources.is_empty() {
+            bail!("no key source: pass --db or --keys, or set db or keys in the configuration");
+        }
+        let (store, _) = self.sources.load()?;
+        let records = store
+            .iter()
+            .map(|(path, ekey)| {
+                let record = KeyRecord::new(path, ekey);
+                match self.details {
+                    true => record.with_details(),
+                    false => record,
+                }
+            })
+            .collect::<Vec<_>>();
+        let format = match (self.format, &self.output) {
+            (Some(format), _) => format,
+            (None, Some(output)) => KeyFormat::of(output).unwrap_or(KeyFormat::Json),
+            (None, None) => KeyFormat::Json,
+        };
+        match &self.output {
+            Some(path) => {
+                let mut output = AtomicFile::create(path)?;
+                let mut writer = BufWriter::new(output.as_file_mut());
+                keys::write_key_file(&mut writer, format, &records)?;
+                writer.flush()?;
+                drop(writer);
+                output.commit()?;
+            }
+            None => {
+                let mut writer = BufWriter::new(io::stdout().lock());
+                keys::write_key_file(&mut writer, format, &records)?;
+                writer.flush()?;
+            }
+        }
+        term::info(format!("{} key(s) exported", records.len()));
+        Ok(EXIT_SUCCESS)
+    }
+}
+
+impl ImportArgs {
+    fn run(&self) -> Result<i32> {
+        let format = KeyFormat::of(&self.store)?;
+        let mut records = match self.store.exists() {
+            true => keys::read_key_file(&self.store)?
+                .into_iter()
+                .map(|(path, ekey)| KeyRecord::new(&path, &ekey))
+                .collect(),
+            false => Vec::new(),
+        };
+        let mut index = records
+            .iter()
+            .enumerate()
+            .map(|(i, record)| (record.file_path.clone(), i))
+            .collect::<HashMap<_, _>>();
+        let (mut added, mut replaced, mut kept) = (0, 0, 0);
+        for file in &self.files {
+            for (path, ekey) in keys::read_key_file(file)? {
+                let Some(&i) = index.get(&path) else {
+                    index.insert(path.clone(), records.len());
+                    records.push(KeyRecord::new(&path, &ekey));
+                    added += 1;
+                    continue;
+                };
+                if records[i].ekey == ekey {
+                    continue;
+                }
+                match self.overwrite {
+                    true => {
+                        records[i].ekey = ekey;
+                        replaced += 1;
+                    }
+                    false => {
+                        term::warn(format!(
+                            "{}: the key of {:?} differs from the one in the store, kept",
+                            path, file
+                        ));
+                        kept += 1;
+                    }
+                }
+            }
+        }
+        let mut output = AtomicFile::create(&self.store)?;
+        let mut writer = BufWriter::new(output.as_file_mut());
+        keys::write_key_file(&mut writer, format, &records)?;
+        writer.flush()?;
+        drop(writer);
+        output.commit()?;
+        term::info(format!(
+            "{} key(s) added, {} replaced, {} conflicting kept, {} in the store",
+            added,
+            replaced,
+            kept,
+            records.len()
+        ));
+        Ok(EXIT_SUCCESS)
+    }
+}
@@ -11,30 +11,90 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
-use anyhow::Result;
-use rusqlite::{Connection, OpenFlags};
-use std::collections::HashMap;
-use std::path::PathBuf;
-
-pub fn get_filename(path: &PathBuf) -> Result<String> {
-    Ok(path
-        .file_name()
+use anyhow::{anyhow, Result};
+use md5::{Digest, Md5};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use umc_qmc::footer;
+use umc_qmc::footer::{FooterParseError, Metadata};
+
+pub fn get_filename(path: &Path) -> Result<String> {
+    path.file_name()
         .and_then(|name| name.to_str())
         .map(|name| name.to_string())
-        .expect("Invalid filename"))
-}
-
-// noinspection SpellCheckingInspection
-pub fn load_db(path: &PathBuf) -> Result<HashMap<String, String>> {
-    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
-    let mut stmt = conn.prepare("SELECT file_path, ekey FROM audio_file_ekey_table")?;
-    let map = stmt
-        .query_map([], |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
-        })?
-        .collect::<rusqlite::Result<HashMap<_, _>>>()?
-        .into_iter()
-        .map(|(key, value)| (get_filename(&PathBuf::from(key)).unwrap(), value))
-        .collect();
-    Ok(map)
+        .ok_or_else(|| anyhow!("invalid file name: {:?}", path))
+}
+
+/// Hex encoded MD5 of the content of `path`.
+pub fn hash(path: &Path) -> Result<String> {
+    let mut hasher = Md5::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Whether the files at `a` and `b` have the same size and hash.
+pub fn same_content(a: &Path, b: &Path) -> Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    Ok(hash(a)? == hash(b)?)
+}
+
+/// Number of trailing components `a` and `b` have in common.
+pub fn common_suffix(a: &[String], b: &[String]) -> usize {
+    a.iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+/// Components of a path, whichever separator it uses.
+pub fn components(path: &str) -> Vec<String> {
+    path.split(['/', '\\'])
+        .filter(|component| !component.is_empty())
+        .map(Into::into)
+        .collect()
+}
+
+/// `path` with `/` separators, to compare paths of any platform.
+pub fn normalize(path: &str) -> String {
+    path.replace('\\', "/")
+}
+
+/// Read the footer at the end of `file`, returning the file size along with the parse result.
+pub fn read_footer<R: Read + Seek>(
+    file: &mut R,
+) -> Result<(u64, Result<Option<Metadata>, FooterParseError>)> {
+    let size = file.seek(SeekFrom::End(0))?;
+    let len = size.min(footer::INITIAL_DETECTION_LEN as u64);
+    let mut buffer = vec![0u8; len as usize];
+    file.seek(SeekFrom::Start(size - len))?;
+    file.read_exact(&mut buffer)?;
+    Ok((size, footer::from_byte_slice(&buffer)))
+}
+
+/// Skip the ID3v2 tag at the current position, if any.
+pub fn skip_id3v2<R: Read + Seek>(reader: &mut R) -> Result<()> {
+    let mut header = [0u8; 10];
+    let start = reader.stream_position()?;
+    match reader.read_exact(&mut header) {
+        Ok(()) if header.starts_with(b"ID3") => {
+            // syncsafe integer, plus the footer when flagged
+            let size = header[6..]
+                .iter()
+                .fold(0u64, |size, &byte| size << 7 | (byte & 0x7F) as u64);
+            let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+            reader.seek(SeekFrom::Start(start + 10 + size + footer))?;
+        }
+        _ => {
+            reader.seek(SeekFrom::Start(start))?;
+        }
+    }
+    Ok(())
 }
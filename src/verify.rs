@@ -0,0 +1,611 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::utils;
+use anyhow::Result;
+use byteorder::{ByteOrder, ReadBytesExt, BE, LE};
+use md5::{Digest, Md5};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use umc_qmc::audio::AudioType;
+
+/// Atoms holding other atoms, walked by [`verify_mp4`].
+const MP4_CONTAINERS: &[&[u8; 4]] = &[
+    b"moov", b"trak", b"mdia", b"minf", b"stbl", b"udta", b"edts", b"dinf", b"mvex", b"moof",
+    b"traf",
+];
+
+/// How a decrypted output looks after parsing its container.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Verified,
+    /// Readable, but with something a complete file would not have, e.g. a truncated last frame.
+    Suspicious,
+    /// Not a valid stream: wrong key, corrupted or truncated data.
+    Broken,
+}
+
+/// A [`Verdict`] with the reason of anything but [`Verdict::Verified`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    pub verdict: Verdict,
+    pub detail: Option<String>,
+}
+
+impl Verification {
+    fn verified() -> Self {
+        Self {
+            verdict: Verdict::Verified,
+            detail: None,
+        }
+    }
+
+    fn suspicious(detail: impl Into<String>) -> Self {
+        Self {
+            verdict: Verdict::Suspicious,
+            detail: Some(detail.into()),
+        }
+    }
+
+    fn broken(detail: impl Into<String>) -> Self {
+        Self {
+            verdict: Verdict::Broken,
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+impl Display for Verification {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let verdict = match self.verdict {
+            Verdict::Verified => "verified",
+            Verdict::Suspicious => "suspicious",
+            Verdict::Broken => "broken",
+        };
+        match &self.detail {
+            Some(detail) => write!(f, "{} ({})", verdict, detail),
+            None => f.write_str(verdict),
+        }
+    }
+}
+
+/// Parse the decrypted `file` as `audio`, or return `None` for audio types which are not
+/// checked. Errors are I/O errors only, problems with the stream are a [`Verification`].
+///
+/// - FLAC: metadata blocks, frame headers and CRCs, sample count and the STREAMINFO MD5.
+/// - Ogg: page sync, sequence numbers, CRCs and the end of stream flag.
+/// - MP3: MPEG frame sync from the first frame to the end or a trailing tag.
+/// - M4A: the atom tree, which must tile the file and contain `ftyp`, `moov` and `mdat`.
+pub fn verify(file: &mut File, audio: AudioType) -> Result<Option<Verification>> {
+    file.seek(SeekFrom::Start(0))?;
+    let size = file.metadata()?.len();
+    let mut reader = BufReader::new(&mut *file);
+    let verification = match audio {
+        AudioType::Flac => verify_flac(&mut reader)?,
+        AudioType::OggVorbis | AudioType::OggOpus => verify_ogg(&mut reader)?,
+        AudioType::Mp3 => verify_mp3(&mut reader, size)?,
+        AudioType::M4a => verify_mp4(&mut reader, size)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(verification))
+}
+
+fn verify_flac<R: Read + Seek>(reader: &mut R) -> Result<Verification> {
+    utils::skip_id3v2(reader)?;
+    let mut flac = match claxon::FlacReader::new(reader) {
+        Ok(flac) => flac,
+        Err(claxon::Error::IoError(err)) if err.kind() != ErrorKind::UnexpectedEof => Err(err)?,
+        Err(err) => return Ok(Verification::broken(err.to_string())),
+    };
+    let info = flac.streaminfo();
+    let bytes = info.bits_per_sample.div_ceil(8) as usize;
+    let mut hasher = Md5::new();
+    let mut samples = 0u64;
+    let mut blocks = flac.blocks();
+    let mut buffer = Vec::new();
+    loop {
+        let block = match blocks.read_next_or_eof(buffer) {
+            Ok(Some(block)) => block,
+            Ok(None) => break,
+            Err(claxon::Error::IoError(err)) if err.kind() != ErrorKind::UnexpectedEof => Err(err)?,
+            Err(claxon::Error::Unsupported(feature)) => {
+                return Ok(Verification::suspicious(format!(
+                    "not checked past {} samples: {} is not supported",
+                    samples, feature
+                )))
+            }
+            Err(err) => {
+                return Ok(Verification::broken(format!(
+                    "after {} samples: {}",
+                    samples, err
+                )))
+            }
+        };
+        for i in 0..block.duration() {
+            for channel in 0..block.channels() {
+                let sample = block.sample(channel, i).to_le_bytes();
+                hasher.update(&sample[..bytes]);
+            }
+        }
+        samples += block.duration() as u64;
+        buffer = block.into_buffer();
+    }
+    if let Some(expected) = info.samples.filter(|&expected| expected != 0) {
+        if samples != expected {
+            return Ok(Verification::broken(format!(
+                "{} of {} samples",
+                samples, expected
+            )));
+        }
+    }
+    if info.md5sum == [0; 16] {
+        return Ok(Verification::verified());
+    }
+    match <[u8; 16]>::from(hasher.finalize()) == info.md5sum {
+        true => Ok(Verification::verified()),
+        false => Ok(Verification::broken(
+            "MD5 of the samples does not match STREAMINFO",
+        )),
+    }
+}
+
+fn verify_ogg<R: Read>(reader: &mut R) -> Result<Verification> {
+    // next expected sequence number and end of stream flag of every logical stream
+    let mut streams = HashMap::<u32, (u32, bool)>::new();
+    let mut pages = 0;
+    loop {
+        let mut header = [0u8; 27];
+        match read_full(reader, &mut header)? {
+            0 => break,
+            27 => {}
+            _ => return Ok(Verification::broken(format!("page {} is truncated", pages))),
+        }
+        if &header[..4] != b"OggS" || header[4] != 0 {
+            return Ok(Verification::broken(format!(
+                "lost page sync after {} pages",
+                pages
+            )));
+        }
+        let mut lacing = vec![0u8; header[26] as usize];
+        let mut body = Vec::new();
+        let complete = read_full(reader, &mut lacing)? == lacing.len() && {
+            body.resize(lacing.iter().map(|&len| len as usize).sum(), 0);
+            read_full(reader, &mut body)? == body.len()
+        };
+        if !complete {
+            return Ok(Verification::broken(format!("page {} is truncated", pages)));
+        }
+        let crc = LE::read_u32(&header[22..26]);
+        header[22..26].fill(0);
+        if ogg_crc(&[&header, &lacing, &body]) != crc {
+            return Ok(Verification::broken(format!(
+                "page {} has a bad CRC",
+                pages
+            )));
+        }
+        let serial = LE::read_u32(&header[14..18]);
+        let sequence = LE::read_u32(&header[18..22]);
+        let (expected, ended) = streams.entry(serial).or_insert((sequence, false));
+        if sequence != *expected {
+            return Ok(Verification::broken(format!(
+                "page {} is missing from stream {:08x}",
+                expected, serial
+            )));
+        }
+        *expected += 1;
+        *ended = header[5] & 0x04 != 0;
+        pages += 1;
+    }
+    if pages == 0 {
+        return Ok(Verification::broken("no Ogg page"));
+    }
+    match streams.values().all(|&(_, ended)| ended) {
+        true => Ok(Verification::verified()),
+        false => Ok(Verification::suspicious("no end of stream page")),
+    }
+}
+
+/// CRC-32 of Ogg pages: polynomial 0x04C11DB7, not reflected, zero initial value.
+fn ogg_crc(parts: &[&[u8]]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = match crc & 0x8000_0000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x04C1_1DB7,
+            };
+        }
+    }
+    crc
+}
+
+fn verify_mp3<R: Read + Seek>(reader: &mut R, size: u64) -> Result<Verification> {
+    utils::skip_id3v2(reader)?;
+    let mut position = reader.stream_position()?;
+    let mut frames = 0;
+    while position < size {
+        let mut header = [0u8; 4];
+        let read = read_full(reader, &mut header)?;
+        if header.starts_with(b"TAG") && size - position == 128
+            || header.starts_with(b"APET")
+            || header.starts_with(b"LYRI")
+        {
+            break;
+        }
+        let Some(len) = mpeg_frame_len(&header[..read]) else {
+            return Ok(match frames {
+                0 => Verification::broken("no MPEG frame"),
+                _ => Verification::broken(format!("lost frame sync after {} frames", frames)),
+            });
+        };
+        if position + len > size {
+            return Ok(Verification::suspicious("the last frame is truncated"));
+        }
+        reader.seek_relative(len as i64 - read as i64)?;
+        position += len;
+        frames += 1;
+    }
+    match frames {
+        0 => Ok(Verification::broken("no MPEG frame")),
+        _ => Ok(Verification::verified()),
+    }
+}
+
+/// Length of the MPEG audio frame starting with `header`, `None` when it is not a valid
+/// header. Free format frames, whose length is unknown, are not supported.
+fn mpeg_frame_len(header: &[u8]) -> Option<u64> {
+    const BITRATES: [[u16; 15]; 5] = [
+        // MPEG 1 layer I, II, III
+        [
+            0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+        ],
+        [
+            0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+        ],
+        [
+            0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+        ],
+        // MPEG 2 and 2.5 layer I, then II and III
+        [
+            0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+        ],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    ];
+    if header.len() < 4 || header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+    let version = (header[1] >> 3) & 0x03;
+    let layer = (header[1] >> 1) & 0x03;
+    let bitrate = (header[2] >> 4) as usize;
+    let sample_rate = ((header[2] >> 2) & 0x03) as usize;
+    let padding = ((header[2] >> 1) & 0x01) as u64;
+    if version == 1 || layer == 0 || bitrate == 0 || bitrate == 15 || sample_rate == 3 {
+        return None;
+    }
+    let mpeg1 = version == 3;
+    let table = match (mpeg1, layer) {
+        (true, 3) => 0,
+        (true, 2) => 1,
+        (true, _) => 2,
+        (false, 3) => 3,
+        (false, _) => 4,
+    };
+    let bitrate = BITRATES[table][bitrate] as u64 * 1000;
+    let sample_rate = [44100, 48000, 32000][sample_rate]
+        >> match version {
+            3 => 0,
+            2 => 1,
+            _ => 2,
+        };
+    let len = match layer {
+        3 => (12 * bitrate / sample_rate + padding) * 4,
+        1 if !mpeg1 => 72 * bitrate / sample_rate + padding,
+        _ => 144 * bitrate / sample_rate + padding,
+    };
+    Some(len)
+}
+
+fn verify_mp4<R: Read + Seek>(reader: &mut R, size: u64) -> Result<Verification> {
+    let mut top = Vec::new();
+    if let Err(detail) = walk_atoms(reader, 0, size, true, &mut top)? {
+        return Ok(Verification::broken(detail));
+    }
+    for required in [b"moov", b"mdat"] {
+        if !top.contains(required) {
+            return Ok(Verification::broken(format!(
+                "no {} atom",
+                String::from_utf8_lossy(required)
+            )));
+        }
+    }
+    match top.first() == Some(b"ftyp") {
+        true => Ok(Verification::verified()),
+        false => Ok(Verification::suspicious("the first atom is not ftyp")),
+    }
+}
+
+/// Check that the atoms between `start` and `end` tile that range, descending into containers.
+/// The names of the atoms are collected in `names`. The inner result describes a broken atom.
+fn walk_atoms<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+    top_level: bool,
+    names: &mut Vec<[u8; 4]>,
+) -> Result<Result<(), String>> {
+    let mut position = start;
+    while position < end {
+        reader.seek(SeekFrom::Start(position))?;
+        let mut header = [0u8; 8];
+        if end - position < 8 || read_full(reader, &mut header)? < 8 {
+            return Ok(Err(format!("truncated atom header at {}", position)));
+        }
+        let name = [header[4], header[5], header[6], header[7]];
+        let printable = String::from_utf8_lossy(&name).into_owned();
+        let (len, header_len) = match BE::read_u32(&header) {
+            1 if end - position >= 16 => (reader.read_u64::<BE>()?, 16),
+            1 => return Ok(Err(format!("truncated atom header at {}", position))),
+            0 if top_level => (end - position, 8),
+            len => (len as u64, 8),
+        };
+        if len < header_len {
+            return Ok(Err(format!(
+                "atom {} has an invalid size {}",
+                printable, len
+            )));
+        }
+        if len > end - position {
+            return Ok(Err(format!(
+                "atom {} extends {} bytes past {}",
+                printable,
+                len - (end - position),
+                match top_level {
+                    true => "the end of the file",
+                    false => "its parent",
+                }
+            )));
+        }
+        if MP4_CONTAINERS.contains(&&name) {
+            let children = walk_atoms(
+                reader,
+                position + header_len,
+                position + len,
+                false,
+                &mut Vec::new(),
+            )?;
+            if children.is_err() {
+                return Ok(children);
+            }
+        }
+        names.push(name);
+        position += len;
+    }
+    Ok(Ok(()))
+}
+
+/// Read until `buffer` is full or the end of the input, returning the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => Err(err)?,
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn file(content: &[u8]) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(content).unwrap();
+        file
+    }
+
+    fn check(content: &[u8], audio: AudioType) -> Verdict {
+        verify(&mut file(content), audio).unwrap().unwrap().verdict
+    }
+
+    /// A FLAC stream of one mono 8-bit frame with `samples`, in a verbatim subframe.
+    fn flac(samples: &[i8], md5: bool) -> Vec<u8> {
+        fn crc8(data: &[u8]) -> u8 {
+            data.iter().fold(0u8, |mut crc, &byte| {
+                crc ^= byte;
+                for _ in 0..8 {
+                    crc = if crc & 0x80 != 0 {
+                        (crc << 1) ^ 0x07
+                    } else {
+                        crc << 1
+                    };
+                }
+                crc
+            })
+        }
+        fn crc16(data: &[u8]) -> u16 {
+            data.iter().fold(0u16, |mut crc, &byte| {
+                crc ^= (byte as u16) << 8;
+                for _ in 0..8 {
+                    crc = if crc & 0x8000 != 0 {
+                        (crc << 1) ^ 0x8005
+                    } else {
+                        crc << 1
+                    };
+                }
+                crc
+            })
+        }
+        let n = samples.len() as u64;
+        let mut stream = b"fLaC\x80\x00\x00\x22".to_vec();
+        stream.extend_from_slice(&(n as u16).to_be_bytes());
+        stream.extend_from_slice(&(n as u16).to_be_bytes());
+        stream.extend_from_slice(&[0; 6]);
+        // 8000 Hz, 1 channel, 8 bits per sample, n samples
+        let packed = (8000u64 << 44) | (7u64 << 36) | n;
+        stream.extend_from_slice(&packed.to_be_bytes());
+        let digest: [u8; 16] =
+            Md5::digest(samples.iter().map(|&s| s as u8).collect::<Vec<_>>()).into();
+        stream.extend_from_slice(&if md5 { digest } else { [0xAA; 16] });
+
+        // block size from the 16-bit field, sample rate and size from STREAMINFO
+        let mut frame = vec![0xFF, 0xF8, 0x70, 0x02, 0x00];
+        frame.extend_from_slice(&(n as u16 - 1).to_be_bytes());
+        frame.push(crc8(&frame));
+        frame.push(0x02); // verbatim subframe
+        frame.extend(samples.iter().map(|&s| s as u8));
+        frame.extend_from_slice(&crc16(&frame).to_be_bytes());
+        stream.extend_from_slice(&frame);
+        stream
+    }
+
+    fn ogg_page(serial: u32, sequence: u32, flags: u8, packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\x00".to_vec();
+        page.push(flags);
+        page.extend_from_slice(&[0; 8]);
+        page.extend_from_slice(&serial.to_le_bytes());
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(1);
+        page.push(packet.len() as u8);
+        page.extend_from_slice(packet);
+        let crc = ogg_crc(&[&page]);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    fn mp3_frame() -> Vec<u8> {
+        // MPEG 1 layer III, 128 kbps, 44100 Hz, no padding: 417 bytes
+        let mut frame = vec![0xFF, 0xFB, 0x90, 0x00];
+        frame.resize(417, 0);
+        frame
+    }
+
+    fn atom(name: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut atom = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        atom.extend_from_slice(name);
+        atom.extend_from_slice(payload);
+        atom
+    }
+
+    #[test]
+    fn test_ogg_crc() {
+        assert_eq!(ogg_crc(&[b"123456789"]), 0x89A1_897F);
+    }
+
+    #[test]
+    fn test_flac() {
+        let samples = [0i8, 12, -7, 100, -128, 5, 9, 3, 1, 2, 3, 4, -1, -2, -3, -4];
+        assert_eq!(
+            check(&flac(&samples, true), AudioType::Flac),
+            Verdict::Verified
+        );
+        assert_eq!(
+            check(&flac(&samples, false), AudioType::Flac),
+            Verdict::Broken
+        );
+        let mut corrupted = flac(&samples, true);
+        let last = corrupted.len() - 5;
+        corrupted[last] ^= 0x01;
+        assert_eq!(check(&corrupted, AudioType::Flac), Verdict::Broken);
+        let truncated = flac(&samples, true);
+        assert_eq!(
+            check(&truncated[..truncated.len() - 4], AudioType::Flac),
+            Verdict::Broken
+        );
+        assert_eq!(check(b"fLaC garbage", AudioType::Flac), Verdict::Broken);
+    }
+
+    #[test]
+    fn test_ogg() {
+        let pages = [
+            ogg_page(1, 0, 0x02, b"OpusHead"),
+            ogg_page(1, 1, 0x00, b"OpusTags"),
+            ogg_page(1, 2, 0x04, b"audio"),
+        ];
+        assert_eq!(
+            check(&pages.concat(), AudioType::OggOpus),
+            Verdict::Verified
+        );
+        assert_eq!(
+            check(&pages[..2].concat(), AudioType::OggOpus),
+            Verdict::Suspicious
+        );
+        let skipped = [pages[0].clone(), pages[2].clone()].concat();
+        assert_eq!(check(&skipped, AudioType::OggOpus), Verdict::Broken);
+        let mut corrupted = pages.concat();
+        *corrupted.last_mut().unwrap() ^= 0x01;
+        assert_eq!(check(&corrupted, AudioType::OggOpus), Verdict::Broken);
+        let truncated = pages.concat();
+        assert_eq!(
+            check(&truncated[..truncated.len() - 2], AudioType::OggOpus),
+            Verdict::Broken
+        );
+    }
+
+    #[test]
+    fn test_mp3() {
+        let frames = [mp3_frame(), mp3_frame(), mp3_frame()].concat();
+        let mut tagged = frames.clone();
+        tagged.extend_from_slice(b"TAG");
+        tagged.resize(frames.len() + 128, 0);
+        assert_eq!(check(&tagged, AudioType::Mp3), Verdict::Verified);
+        assert_eq!(
+            check(&frames[..frames.len() - 10], AudioType::Mp3),
+            Verdict::Suspicious
+        );
+        let mut garbage = frames.clone();
+        garbage[417] = 0;
+        assert_eq!(check(&garbage, AudioType::Mp3), Verdict::Broken);
+        assert_eq!(check(&[0u8; 1000], AudioType::Mp3), Verdict::Broken);
+    }
+
+    #[test]
+    fn test_mp4() {
+        let trak = atom(b"trak", &atom(b"tkhd", &[0; 12]));
+        let moov = atom(b"moov", &trak);
+        let mp4 = [
+            atom(b"ftyp", b"M4A \0\0\0\0"),
+            moov,
+            atom(b"mdat", &[1; 32]),
+        ]
+        .concat();
+        assert_eq!(check(&mp4, AudioType::M4a), Verdict::Verified);
+        assert_eq!(
+            check(&mp4[..mp4.len() - 1], AudioType::M4a),
+            Verdict::Broken
+        );
+        let mut bad_child = mp4.clone();
+        // tkhd claims more than trak holds
+        bad_child[16 + 8 + 8 + 3] = 0x40;
+        assert_eq!(check(&bad_child, AudioType::M4a), Verdict::Broken);
+        let no_moov = [atom(b"ftyp", b"M4A \0\0\0\0"), atom(b"mdat", &[1; 32])].concat();
+        assert_eq!(check(&no_moov, AudioType::M4a), Verdict::Broken);
+    }
+
+    #[test]
+    fn test_unchecked() {
+        assert!(verify(&mut file(b"RIFF"), AudioType::Wav)
+            .unwrap()
+            .is_none());
+    }
+}
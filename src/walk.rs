@@ -0,0 +1,103 @@
+/*
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::term;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A file found under the input directory.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Canonical path of the file.
+    pub path: PathBuf,
+
+    /// Path relative to the input directory, used to mirror the layout under the output directory.
+    pub relative: PathBuf,
+}
+
+/// Collect the files under `root`.
+///
+/// Subdirectories are only descended into when `recursive` is set, otherwise they are skipped.
+/// Directories are identified by their canonical path, so a symlink pointing back to one of its
+/// ancestors is visited at most once. The `exclude` directory (e.g. an output directory nested
+/// inside the input) is never entered.
+pub fn collect(
+    root: &Path,
+    exclude: Option<&Path>,
+    recursive: bool,
+    verbose: bool,
+) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut visited = HashSet::new();
+    visited.insert(fs::canonicalize(root)?);
+    if let Some(exclude) = exclude.and_then(|path| fs::canonicalize(path).ok()) {
+        visited.insert(exclude);
+    }
+    visit(
+        root,
+        Path::new(""),
+        recursive,
+        verbose,
+        &mut visited,
+        &mut entries,
+    )?;
+    entries.sort_by(|a, b| a.relative.cmp(&b.relative));
+    Ok(entries)
+}
+
+fn visit(
+    dir: &Path,
+    relative: &Path,
+    recursive: bool,
+    verbose: bool,
+    visited: &mut HashSet<PathBuf>,
+    entries: &mut Vec<Entry>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = relative.join(entry.file_name());
+        let canonical = match fs::canonicalize(&path) {
+            Ok(canonical) => canonical,
+            Err(err) => {
+                term::warn(format!("skipping {:?}: {}", path, err));
+                continue;
+            }
+        };
+        if canonical.is_dir() {
+            if !recursive {
+                if verbose {
+                    println!("skipping directory {:?}", path);
+                }
+                continue;
+            }
+            if !visited.insert(canonical) {
+                if verbose {
+                    println!("skipping {:?}: already visited", path);
+                }
+                continue;
+            }
+            if let Err(err) = visit(&path, &relative, recursive, verbose, visited, entries) {
+                term::warn(format!("skipping {:?}: {}", path, err));
+            }
+        } else if canonical.is_file() {
+            entries.push(Entry {
+                path: canonical,
+                relative,
+            });
+        }
+    }
+    Ok(())
+}
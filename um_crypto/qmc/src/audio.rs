@@ -0,0 +1,201 @@
+/*!
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::fmt::{Display, Formatter};
+
+/// Number of decrypted bytes [`detect`] looks at.
+pub const SNIFF_LEN: usize = 4096;
+
+const ASF_GUID: [u8; 16] = [
+    0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE, 0x6C,
+];
+
+/// Container of decrypted audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioType {
+    Flac,
+    OggVorbis,
+    OggOpus,
+    Mp3,
+    M4a,
+    Wav,
+    Ape,
+    Wma,
+    Dff,
+    Dsf,
+}
+
+pub const AUDIO_TYPES: &[AudioType] = &[
+    AudioType::Flac,
+    AudioType::OggVorbis,
+    AudioType::OggOpus,
+    AudioType::Mp3,
+    AudioType::M4a,
+    AudioType::Wav,
+    AudioType::Ape,
+    AudioType::Wma,
+    AudioType::Dff,
+    AudioType::Dsf,
+];
+
+impl AudioType {
+    /// Conventional file extension, without the leading dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioType::Flac => "flac",
+            AudioType::OggVorbis => "ogg",
+            AudioType::OggOpus => "opus",
+            AudioType::Mp3 => "mp3",
+            AudioType::M4a => "m4a",
+            AudioType::Wav => "wav",
+            AudioType::Ape => "ape",
+            AudioType::Wma => "wma",
+            AudioType::Dff => "dff",
+            AudioType::Dsf => "dsf",
+        }
+    }
+}
+
+impl Display for AudioType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            AudioType::Flac => "FLAC",
+            AudioType::OggVorbis => "Ogg Vorbis",
+            AudioType::OggOpus => "Ogg Opus",
+            AudioType::Mp3 => "MP3",
+            AudioType::M4a => "M4A",
+            AudioType::Wav => "WAV",
+            AudioType::Ape => "APE",
+            AudioType::Wma => "WMA",
+            AudioType::Dff => "DFF",
+            AudioType::Dsf => "DSF",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Whether `extension` (without the leading dot) is the extension of any [`AudioType`].
+pub fn is_audio_extension(extension: &str) -> bool {
+    extension.eq_ignore_ascii_case("mp4")
+        || AUDIO_TYPES
+            .iter()
+            .any(|audio| audio.extension().eq_ignore_ascii_case(extension))
+}
+
+/// MPEG audio frame header: sync word, a defined layer, bitrate and sample rate.
+fn is_mpeg_frame(header: &[u8]) -> bool {
+    header.len() >= 4
+        && header[0] == 0xFF
+        && header[1] & 0xE0 == 0xE0
+        && header[1] & 0x06 != 0
+        && header[2] & 0xF0 != 0xF0
+        && header[2] & 0x0C != 0x0C
+}
+
+/// Detect the container from the first decrypted bytes, ideally [`SNIFF_LEN`] of them.
+pub fn detect(header: &[u8]) -> Option<AudioType> {
+    if header.starts_with(b"ID3") && header.len() >= 10 {
+        // Skip the ID3v2 tag (syncsafe size, plus an optional footer), the audio follows it.
+        let size = header[6..10]
+            .iter()
+            .fold(0usize, |size, &b| (size << 7) | (b & 0x7F) as usize);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        return match header.get(10 + size + footer..) {
+            Some(rest) if !rest.is_empty() => detect(rest).or(Some(AudioType::Mp3)),
+            _ => Some(AudioType::Mp3),
+        };
+    }
+    if header.starts_with(b"fLaC") {
+        return Some(AudioType::Flac);
+    }
+    if header.starts_with(b"OggS") {
+        return match header.get(28..) {
+            Some(codec) if codec.starts_with(b"OpusHead") => Some(AudioType::OggOpus),
+            _ => Some(AudioType::OggVorbis),
+        };
+    }
+    if header.len() >= 12 && &header[4..8] == b"ftyp" {
+        return Some(AudioType::M4a);
+    }
+    if header.len() >= 12 && header.starts_with(b"RIFF") && &header[8..12] == b"WAVE" {
+        return Some(AudioType::Wav);
+    }
+    if header.starts_with(b"MAC ") {
+        return Some(AudioType::Ape);
+    }
+    if header.starts_with(&ASF_GUID) {
+        return Some(AudioType::Wma);
+    }
+    if header.len() >= 16 && header.starts_with(b"FRM8") && &header[12..16] == b"DSD " {
+        return Some(AudioType::Dff);
+    }
+    if header.starts_with(b"DSD ") {
+        return Some(AudioType::Dsf);
+    }
+    if is_mpeg_frame(header) {
+        return Some(AudioType::Mp3);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_magic() {
+        assert_eq!(detect(b"fLaC\x00\x00\x00\x22"), Some(AudioType::Flac));
+        assert_eq!(detect(b"MAC \x96\x0f\x00\x00"), Some(AudioType::Ape));
+        assert_eq!(detect(b"DSD \x1c\x00\x00\x00"), Some(AudioType::Dsf));
+        assert_eq!(
+            detect(b"FRM8\x00\x00\x00\x00\x00\x00\x10\x00DSD "),
+            Some(AudioType::Dff)
+        );
+        assert_eq!(
+            detect(b"RIFF\x24\x08\x00\x00WAVEfmt "),
+            Some(AudioType::Wav)
+        );
+        assert_eq!(
+            detect(b"\x00\x00\x00\x20ftypM4A \x00\x00\x00\x00"),
+            Some(AudioType::M4a)
+        );
+        assert_eq!(detect(&ASF_GUID), Some(AudioType::Wma));
+        assert_eq!(detect(b"hello world"), None);
+    }
+
+    #[test]
+    fn test_detect_ogg() {
+        let mut page = [0u8; 36];
+        page[..4].copy_from_slice(b"OggS");
+        page[28..36].copy_from_slice(b"OpusHead");
+        assert_eq!(detect(&page), Some(AudioType::OggOpus));
+        page[28..35].copy_from_slice(b"\x01vorbis");
+        assert_eq!(detect(&page), Some(AudioType::OggVorbis));
+    }
+
+    #[test]
+    fn test_detect_mp3() {
+        assert_eq!(detect(b"\xFF\xFB\x90\x64"), Some(AudioType::Mp3));
+        // ADTS (layer 0) is not MPEG audio
+        assert_eq!(detect(b"\xFF\xF1\x50\x80"), None);
+        assert_eq!(
+            detect(b"ID3\x04\x00\x00\x00\x00\x00\x00"),
+            Some(AudioType::Mp3)
+        );
+        // FLAC prefixed by a 4 byte ID3 tag
+        assert_eq!(
+            detect(b"ID3\x04\x00\x00\x00\x00\x00\x04\x00\x00\x00\x00fLaC"),
+            Some(AudioType::Flac)
+        );
+    }
+}
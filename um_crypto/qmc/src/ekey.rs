@@ -42,6 +42,40 @@ pub enum EKeyDecryptError {
     FailDecryptV2(TcTeaError),
 }
 
+#[derive(Debug, PartialEq, Error)]
+pub enum EKeyEncryptError {
+    #[error("Key is too short for encryption, require at least 8 bytes")]
+    KeyTooShort,
+    #[error("Error when encrypting ekey: {0}")]
+    FailEncrypt(TcTeaError),
+}
+
+/// Envelope of an ekey.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EKeyVersion {
+    V1,
+    /// Prefixed by "QQMusic EncV2,Key:"
+    V2,
+}
+
+impl EKeyVersion {
+    pub fn detect<T: AsRef<[u8]>>(ekey: T) -> Self {
+        match ekey.as_ref().starts_with(EKEY_V2_PREFIX) {
+            true => EKeyVersion::V2,
+            false => EKeyVersion::V1,
+        }
+    }
+}
+
+fn make_tea_key(header: &[u8]) -> Vec<u8> {
+    // tea_key: interleave a byte from each stream
+    EKEY_SIMPLE_KEY
+        .iter()
+        .zip(header)
+        .flat_map(|(&simple_key_part, &header_part)| [simple_key_part, header_part])
+        .collect_vec()
+}
+
 fn make_simple_key<const N: usize>() -> [u8; N] {
     let mut result = [0u8; N];
 
@@ -62,13 +96,7 @@ pub fn decrypt_v1(ekey: &[u8]) -> Result<Vec<u8>> {
 
     let ekey = base64::decode(ekey)?;
     let (header, cipher) = ekey.split_at(8);
-
-    // tea_key: interleave a byte from each stream
-    let tea_key = EKEY_SIMPLE_KEY
-        .iter()
-        .zip(header)
-        .flat_map(|(&simple_key_part, &header_part)| [simple_key_part, header_part])
-        .collect_vec();
+    let tea_key = make_tea_key(header);
 
     let plaintext = tc_tea::decrypt(cipher, tea_key).map_err(EKeyDecryptError::FailDecryptV1)?;
     Ok([header, &plaintext].concat())
@@ -90,3 +118,70 @@ pub fn decrypt<T: AsRef<[u8]>>(ekey: T) -> Result<Vec<u8>> {
         None => decrypt_v1(ekey),
     }
 }
+
+/// Encrypt a key into a v1 ekey.
+///
+/// Uses a zero salt, so the output is deterministic (which is what fixtures want).
+pub fn encrypt_v1(key: &[u8]) -> Result<String> {
+    if key.len() < 8 {
+        Err(EKeyEncryptError::KeyTooShort)?;
+    }
+
+    let (header, plaintext) = key.split_at(8);
+    let tea_key = make_tea_key(header);
+    let cipher = tc_tea::encrypt_with_salt(plaintext, tea_key, &[0u8; 10])
+        .map_err(EKeyEncryptError::FailEncrypt)?;
+    Ok(base64::encode([header, &cipher].concat()))
+}
+
+/// Encrypt a key into a v2 ekey, without the "QQMusic EncV2,Key:" prefix.
+pub fn encrypt_v2(key: &[u8]) -> Result<String> {
+    let ekey = encrypt_v1(key)?;
+    let ekey = tc_tea::encrypt_with_salt(ekey, EKEY_V2_KEY2, &[0u8; 10])
+        .map_err(EKeyEncryptError::FailEncrypt)?;
+    let ekey = tc_tea::encrypt_with_salt(ekey, EKEY_V2_KEY1, &[0u8; 10])
+        .map_err(EKeyEncryptError::FailEncrypt)?;
+    Ok(base64::encode(ekey))
+}
+
+/// Encrypt a key into an ekey of the given envelope, the inverse of [`decrypt`].
+pub fn encrypt<T: AsRef<[u8]>>(key: T, version: EKeyVersion) -> Result<String> {
+    let key = key.as_ref();
+    match version {
+        EKeyVersion::V1 => encrypt_v1(key),
+        EKeyVersion::V2 => {
+            let prefix = String::from_utf8_lossy(EKEY_V2_PREFIX);
+            Ok(format!("{}{}", prefix, encrypt_v2(key)?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::generate_bytes;
+
+    #[test]
+    fn test_round_trip() {
+        for (seed, key_len) in [8usize, 9, 16, 128, 256, 300, 301, 512, 704]
+            .into_iter()
+            .enumerate()
+        {
+            let key = generate_bytes(key_len, seed as u64);
+            for version in [EKeyVersion::V1, EKeyVersion::V2] {
+                let ekey = encrypt(&key, version).expect("should encrypt");
+                assert_eq!(EKeyVersion::detect(&ekey), version);
+                assert_eq!(decrypt(&ekey).expect("should decrypt"), key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_too_short() {
+        let err = encrypt_v1(b"1234567").expect_err("should fail");
+        assert_eq!(
+            err.downcast_ref::<EKeyEncryptError>(),
+            Some(&EKeyEncryptError::KeyTooShort)
+        );
+    }
+}
@@ -12,7 +12,9 @@
  * limitations under the License.
  */
 use crate::footer::utils::is_base64;
-use crate::footer::{Data, FooterParseError, Metadata, MetadataParser};
+use crate::footer::{
+    Data, FooterParseError, FooterWriteError, Metadata, MetadataParser, MetadataWriter,
+};
 use byteorder::{ByteOrder, BE};
 use itertools::Itertools;
 
@@ -64,3 +66,18 @@ impl MetadataParser for QTagMetadata {
         Ok(None)
     }
 }
+
+impl MetadataWriter for QTagMetadata {
+    fn to_bytes(&self, ekey: Option<&str>) -> Result<Vec<u8>, FooterWriteError> {
+        let ekey = ekey.ok_or(FooterWriteError::EKeyMissing("Android/QTag"))?;
+        if !is_base64(ekey.as_bytes()) {
+            Err(FooterWriteError::EKeyInvalid(ekey.to_string()))?;
+        }
+
+        let payload = format!("{},{},2", ekey, self.resource_id);
+        let mut footer = payload.into_bytes();
+        footer.extend_from_slice(&(footer.len() as u32).to_be_bytes());
+        footer.extend_from_slice(b"QTag");
+        Ok(footer)
+    }
+}
@@ -11,7 +11,9 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
-use crate::footer::{Data, FooterParseError, Metadata, MetadataParser};
+use crate::footer::{
+    Data, FooterParseError, FooterWriteError, Metadata, MetadataParser, MetadataWriter,
+};
 use byteorder::{ByteOrder, BE};
 use itertools::Itertools;
 
@@ -63,3 +65,23 @@ impl MetadataParser for STagMetadata {
         Ok(None)
     }
 }
+
+impl MetadataWriter for STagMetadata {
+    fn to_bytes(&self, ekey: Option<&str>) -> Result<Vec<u8>, FooterWriteError> {
+        if ekey.is_some() {
+            Err(FooterWriteError::EKeyUnsupported("Android/STag"))?;
+        }
+        if self.media_mid.contains(',') {
+            Err(FooterWriteError::InvalidField(
+                "media_mid",
+                self.media_mid.clone(),
+            ))?;
+        }
+
+        let payload = format!("{},2,{}", self.resource_id, self.media_mid);
+        let mut footer = payload.into_bytes();
+        footer.extend_from_slice(&(footer.len() as u32).to_be_bytes());
+        footer.extend_from_slice(b"STag");
+        Ok(footer)
+    }
+}
@@ -26,6 +26,9 @@ use thiserror::Error;
 
 pub const INITIAL_DETECTION_LEN: usize = 1024;
 
+/// Length of a media mid, e.g. `001y7CaR29k6YP`.
+pub const MEDIA_MID_LEN: usize = 14;
+
 #[derive(Error, Debug)]
 pub enum FooterParseError {
     #[error("Footer: Buffer too small, require at least {0} bytes")]
@@ -60,6 +63,20 @@ pub enum FooterParseError {
     StringToIntError(String),
 }
 
+#[derive(Error, Debug, PartialEq)]
+pub enum FooterWriteError {
+    #[error("Footer: {0} requires an ekey")]
+    EKeyMissing(&'static str),
+    #[error("Footer: {0} can't carry an ekey")]
+    EKeyUnsupported(&'static str),
+    #[error("Footer: Invalid EKey: {0}")]
+    EKeyInvalid(String),
+    #[error("Footer: EKey is too large (len={0})")]
+    EKeyTooLarge(usize),
+    #[error("Footer: Field '{0}' can't be written: {1}")]
+    InvalidField(&'static str, String),
+}
+
 /// Footer type
 #[derive(Debug, Clone, PartialEq)]
 pub enum Data {
@@ -74,6 +91,48 @@ pub enum Data {
     AndroidSTag(android_stag::STagMetadata),
 }
 
+impl Data {
+    /// Short name of the footer type.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Data::PCv1Legacy(_) => "PCv1/EKey",
+            Data::PCv2MusicEx(_) => "PCv2/MusicEx",
+            Data::AndroidQTag(_) => "Android/QTag",
+            Data::AndroidSTag(_) => "Android/STag",
+        }
+    }
+
+    /// Song id, only known to "MusicEx" footers.
+    pub fn mid(&self) -> Option<&str> {
+        match self {
+            Data::PCv2MusicEx(data) if !data.mid.is_empty() => Some(&data.mid),
+            _ => None,
+        }
+    }
+
+    /// Id of the audio file (`file.media_mid`).
+    ///
+    /// "MusicEx" footers only keep the file name, see [`media_mid_of`].
+    pub fn media_mid(&self) -> Option<&str> {
+        match self {
+            Data::AndroidSTag(data) => Some(data.media_mid.as_str()),
+            Data::PCv2MusicEx(data) => media_mid_of(&data.media_filename),
+            _ => None,
+        }
+        .filter(|media_mid| !media_mid.is_empty())
+    }
+
+    /// Numeric id of the resource, found in Android footers.
+    pub fn resource_id(&self) -> Option<u64> {
+        match self {
+            Data::AndroidQTag(data) => Some(data.resource_id),
+            Data::AndroidSTag(data) => Some(data.resource_id),
+            _ => None,
+        }
+        .filter(|&resource_id| resource_id != 0)
+    }
+}
+
 /// File Footer metadata
 #[derive(Debug, Clone, PartialEq)]
 pub struct Metadata {
@@ -91,6 +150,32 @@ pub trait MetadataParser {
     fn from_byte_slice(buffer: &[u8]) -> Result<Option<Metadata>, FooterParseError>;
 }
 
+pub trait MetadataWriter {
+    /// Serialize back to the trailer bytes, with the (not decrypted) `ekey` when the footer has one.
+    fn to_bytes(&self, ekey: Option<&str>) -> Result<Vec<u8>, FooterWriteError>;
+}
+
+impl Metadata {
+    /// Serialize back to the trailer bytes, the inverse of [`from_byte_slice`].
+    pub fn to_bytes(&self) -> Result<Vec<u8>, FooterWriteError> {
+        let ekey = self.ekey.as_deref();
+        match &self.data {
+            Data::PCv1Legacy(data) => data.to_bytes(ekey),
+            Data::PCv2MusicEx(data) => data.to_bytes(ekey),
+            Data::AndroidQTag(data) => data.to_bytes(ekey),
+            Data::AndroidSTag(data) => data.to_bytes(ekey),
+        }
+    }
+}
+
+/// Media mid of a file named like `F0M0001y7CaR29k6YP.mflac`: a 4-character type prefix
+/// (e.g. `F0M0`), the media mid and the extension, as the PC client names its media files.
+pub fn media_mid_of(filename: &str) -> Option<&str> {
+    let stem = filename.rsplit_once('.').map_or(filename, |(stem, _)| stem);
+    (stem.len() == MEDIA_MID_LEN + 4 && stem.bytes().all(|c| c.is_ascii_alphanumeric()))
+        .then(|| &stem[4..])
+}
+
 pub fn from_byte_slice(buffer: &[u8]) -> Result<Option<Metadata>, FooterParseError> {
     if let Some(metadata) = STagMetadata::from_byte_slice(buffer)? {
         return Ok(Some(metadata));
@@ -177,8 +262,87 @@ mod tests {
             payload.data,
             Data::PCv2MusicEx(PcV2MusicEx {
                 mid: "AaBbCcDdEeFfGg".into(),
-                media_filename: "F0M000112233445566.mflac".into()
+                media_filename: "F0M000112233445566.mflac".into(),
+                unknown: [0x01010101, 0x02020202, 0x03030303, 0x04040404],
             })
         )
     }
+
+    #[test]
+    fn test_ids() {
+        let parse = |fixture: &[u8]| from_byte_slice(fixture).unwrap().unwrap().data;
+        let qtag = parse(include_bytes!("fixtures/ekey_android_qtag.bin"));
+        assert_eq!(qtag.resource_id(), Some(326454301));
+        assert_eq!(qtag.media_mid(), None);
+        let stag = parse(include_bytes!("fixtures/ekey_android_stag.bin"));
+        assert_eq!(stag.media_mid(), Some("001y7CaR29k6YP"));
+        assert_eq!(stag.resource_id(), Some(5177785));
+        let musicex = parse(include_bytes!("fixtures/ekey_pc_enc_v2.bin"));
+        assert_eq!(musicex.mid(), Some("AaBbCcDdEeFfGg"));
+        assert_eq!(musicex.media_mid(), Some("00112233445566"));
+        assert_eq!(musicex.resource_id(), None);
+    }
+
+    #[test]
+    fn test_media_mid_of() {
+        assert_eq!(
+            media_mid_of("F0M0001y7CaR29k6YP.mflac"),
+            Some("001y7CaR29k6YP")
+        );
+        assert_eq!(media_mid_of("O6M0001y7CaR29k6YP"), Some("001y7CaR29k6YP"));
+        assert_eq!(media_mid_of("001y7CaR29k6YP.mflac"), None);
+        assert_eq!(media_mid_of("F0M0 Artist - Song.mflac"), None);
+        assert_eq!(media_mid_of("F0M0001y7CaR29k6\u{e9}.mflac"), None);
+    }
+
+    #[test]
+    fn test_pc_enc_v2_short() {
+        let fixture = include_bytes!("fixtures/ekey_pc_enc_v2.bin");
+        let footer = &fixture[fixture.len() - 0x40..];
+        assert!(matches!(
+            from_byte_slice(footer),
+            Err(FooterParseError::BufferTooSmall(0xC0))
+        ));
+        assert!(musicex_v1::MusicExV1::from_bytes(&[0; 0x20]).is_err());
+    }
+
+    fn assert_round_trip(fixture: &[u8]) {
+        let metadata = from_byte_slice(fixture)
+            .expect("Should not fail")
+            .expect("should parse");
+        let bytes = metadata.to_bytes().expect("should serialize");
+        assert_eq!(bytes.len(), metadata.size);
+        assert_eq!(bytes, fixture[fixture.len() - metadata.size..]);
+        assert_eq!(from_byte_slice(&bytes).unwrap(), Some(metadata));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_round_trip(include_bytes!("fixtures/ekey_android_qtag.bin"));
+        assert_round_trip(include_bytes!("fixtures/ekey_android_stag.bin"));
+        assert_round_trip(include_bytes!("fixtures/ekey_pc_enc_v1.bin"));
+        assert_round_trip(include_bytes!("fixtures/ekey_pc_enc_v2.bin"));
+    }
+
+    #[test]
+    fn test_write_errors() {
+        let qtag = QTagMetadata { resource_id: 1 };
+        assert_eq!(
+            qtag.to_bytes(None),
+            Err(FooterWriteError::EKeyMissing("Android/QTag"))
+        );
+        assert!(qtag.to_bytes(Some("a,b")).is_err());
+        let stag = STagMetadata {
+            media_mid: "a,b".into(),
+            resource_id: 1,
+        };
+        assert!(stag.to_bytes(None).is_err());
+        let musicex = PcV2MusicEx {
+            mid: "M".repeat(31),
+            media_filename: "".into(),
+            unknown: [0; 4],
+        };
+        assert!(musicex.to_bytes(None).is_err());
+        assert!(PcV1Legacy.to_bytes(Some(&"A".repeat(0x501))).is_err());
+    }
 }
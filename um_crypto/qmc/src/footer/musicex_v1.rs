@@ -12,10 +12,13 @@
  * limitations under the License.
  */
 use crate::footer::pc_v2_musicex::PcV2MusicEx;
-use crate::footer::utils::from_ascii_utf16;
-use crate::footer::{Data, FooterParseError, Metadata};
-use byteorder::{ByteOrder, ReadBytesExt, LE};
-use std::io::{Cursor, Read};
+use crate::footer::utils::{from_ascii_utf16, to_ascii_utf16};
+use crate::footer::{Data, FooterParseError, FooterWriteError, Metadata};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, LE};
+use std::io::{Cursor, Read, Write};
+
+/// Size of the whole `MusicEx` v1 footer.
+pub const PAYLOAD_SIZE: usize = 0xC0;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct MusicExV1 {
@@ -50,29 +53,73 @@ impl Default for MusicExV1 {
 
 impl MusicExV1 {
     pub fn from_bytes(buffer: &[u8]) -> anyhow::Result<MusicExV1> {
-        assert_eq!(buffer.len(), 0xC0 - 0x10);
+        anyhow::ensure!(
+            buffer.len() == PAYLOAD_SIZE - 0x10,
+            "expected {} bytes, got {}",
+            PAYLOAD_SIZE - 0x10,
+            buffer.len()
+        );
 
         let mut cursor = Cursor::new(&buffer);
-        let mut result = MusicExV1::default();
-        result.unknown_0 = cursor.read_u32::<LE>()?;
-        result.unknown_1 = cursor.read_u32::<LE>()?;
-        result.unknown_2 = cursor.read_u32::<LE>()?;
-        cursor.read(&mut result.mid)?;
-        cursor.read(&mut result.media_filename)?;
+        let mut result = MusicExV1 {
+            unknown_0: cursor.read_u32::<LE>()?,
+            unknown_1: cursor.read_u32::<LE>()?,
+            unknown_2: cursor.read_u32::<LE>()?,
+            ..Default::default()
+        };
+        // a short read would leave the rest of the fields zeroed
+        cursor.read_exact(&mut result.mid)?;
+        cursor.read_exact(&mut result.media_filename)?;
         result.unknown_3 = cursor.read_u32::<LE>()?;
 
         Ok(result)
     }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut cursor = Cursor::new(Vec::with_capacity(PAYLOAD_SIZE - 0x10));
+        // writing to a Vec can't fail
+        cursor.write_u32::<LE>(self.unknown_0).unwrap();
+        cursor.write_u32::<LE>(self.unknown_1).unwrap();
+        cursor.write_u32::<LE>(self.unknown_2).unwrap();
+        cursor.write_all(&self.mid).unwrap();
+        cursor.write_all(&self.media_filename).unwrap();
+        cursor.write_u32::<LE>(self.unknown_3).unwrap();
+        cursor.into_inner()
+    }
+}
+
+impl TryFrom<&PcV2MusicEx> for MusicExV1 {
+    type Error = FooterWriteError;
+
+    fn try_from(value: &PcV2MusicEx) -> Result<Self, Self::Error> {
+        let [unknown_0, unknown_1, unknown_2, unknown_3] = value.unknown;
+        let mut result = MusicExV1 {
+            unknown_0,
+            unknown_1,
+            unknown_2,
+            unknown_3,
+            ..Default::default()
+        };
+        to_ascii_utf16(&value.mid, &mut result.mid)
+            .ok_or_else(|| FooterWriteError::InvalidField("mid", value.mid.clone()))?;
+        to_ascii_utf16(&value.media_filename, &mut result.media_filename).ok_or_else(|| {
+            FooterWriteError::InvalidField("media_filename", value.media_filename.clone())
+        })?;
+        Ok(result)
+    }
 }
 
 pub fn parse_v1(footer: &[u8]) -> Result<Option<Metadata>, FooterParseError> {
     let (payload, payload_len) = footer.split_at(footer.len() - 4);
-    let payload_len = LE::read_u32(&payload_len) as usize;
-    if payload_len != 0xC0 {
+    let payload_len = LE::read_u32(payload_len) as usize;
+    if payload_len != PAYLOAD_SIZE {
         Err(FooterParseError::PCv2MusicExUnsupportedPayloadSize(
             payload_len,
         ))?;
     }
+    if payload.len() < payload_len - 0x10 {
+        Err(FooterParseError::BufferTooSmall(PAYLOAD_SIZE))?;
+    }
 
     let payload = &payload[payload.len() - (payload_len - 0x10)..];
     let payload =
@@ -86,6 +133,12 @@ pub fn parse_v1(footer: &[u8]) -> Result<Option<Metadata>, FooterParseError> {
         data: Data::PCv2MusicEx(PcV2MusicEx {
             mid,
             media_filename,
+            unknown: [
+                payload.unknown_0,
+                payload.unknown_1,
+                payload.unknown_2,
+                payload.unknown_3,
+            ],
         }),
     }))
 }
@@ -12,7 +12,9 @@
  * limitations under the License.
  */
 use crate::footer::utils::is_base64;
-use crate::footer::{Data, FooterParseError, Metadata, MetadataParser};
+use crate::footer::{
+    Data, FooterParseError, FooterWriteError, Metadata, MetadataParser, MetadataWriter,
+};
 use byteorder::{ByteOrder, LE};
 
 pub const MAX_ALLOWED_EKEY_LEN: usize = 0x500;
@@ -41,7 +43,7 @@ impl MetadataParser for PcV1Legacy {
         let ekey = payload
             .iter()
             .take_while(|&&b| b != 0)
-            .map(|&b| b)
+            .copied()
             .collect::<Vec<_>>();
         let ekey = String::from_utf8_lossy(ekey.as_slice());
         if !is_base64(ekey.as_bytes()) {
@@ -55,3 +57,19 @@ impl MetadataParser for PcV1Legacy {
         }))
     }
 }
+
+impl MetadataWriter for PcV1Legacy {
+    fn to_bytes(&self, ekey: Option<&str>) -> Result<Vec<u8>, FooterWriteError> {
+        let ekey = ekey.ok_or(FooterWriteError::EKeyMissing("PCv1/EKey"))?;
+        if ekey.len() > MAX_ALLOWED_EKEY_LEN {
+            Err(FooterWriteError::EKeyTooLarge(ekey.len()))?;
+        }
+        if !is_base64(ekey.as_bytes()) {
+            Err(FooterWriteError::EKeyInvalid(ekey.to_string()))?;
+        }
+
+        let mut footer = ekey.as_bytes().to_vec();
+        footer.extend_from_slice(&(ekey.len() as u32).to_le_bytes());
+        Ok(footer)
+    }
+}
@@ -11,7 +11,9 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
-use crate::footer::{musicex_v1, FooterParseError, Metadata, MetadataParser};
+use crate::footer::{
+    musicex_v1, FooterParseError, FooterWriteError, Metadata, MetadataParser, MetadataWriter,
+};
 use byteorder::{ByteOrder, LE};
 
 #[derive(Debug, Clone, PartialEq)]
@@ -21,6 +23,9 @@ pub struct PcV2MusicEx {
 
     /// The actual file name used for `ekey` lookup (`.file.media_mid` + extension).
     pub media_filename: String,
+
+    /// Fields of unknown meaning, kept to write the footer back unchanged.
+    pub unknown: [u32; 4],
 }
 
 impl MetadataParser for PcV2MusicEx {
@@ -42,3 +47,17 @@ impl MetadataParser for PcV2MusicEx {
         Ok(None)
     }
 }
+
+impl MetadataWriter for PcV2MusicEx {
+    fn to_bytes(&self, ekey: Option<&str>) -> Result<Vec<u8>, FooterWriteError> {
+        if ekey.is_some() {
+            Err(FooterWriteError::EKeyUnsupported("PCv2/MusicEx"))?;
+        }
+
+        let mut footer = musicex_v1::MusicExV1::try_from(self)?.to_bytes();
+        footer.extend_from_slice(&(musicex_v1::PAYLOAD_SIZE as u32).to_le_bytes());
+        footer.extend_from_slice(&1u32.to_le_bytes());
+        footer.extend_from_slice(b"musicex\x00");
+        Ok(footer)
+    }
+}
@@ -28,3 +28,15 @@ pub fn from_ascii_utf16(data: &[u8]) -> String {
         .collect::<Vec<_>>();
     String::from_utf8_lossy(&data).to_string()
 }
+
+/// Convert an ASCII string to a zero padded UTF-16 LE buffer, `None` if it does not fit.
+pub fn to_ascii_utf16(value: &str, buffer: &mut [u8]) -> Option<()> {
+    if !value.is_ascii() || value.contains('\0') || value.len() * 2 > buffer.len() {
+        return None;
+    }
+    buffer.fill(0);
+    for (chunk, b) in buffer.chunks_exact_mut(2).zip(value.bytes()) {
+        chunk[0] = b;
+    }
+    Some(())
+}
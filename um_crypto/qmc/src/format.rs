@@ -0,0 +1,168 @@
+/*!
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::audio::is_audio_extension;
+use crate::footer::Metadata;
+
+/// Cipher used by a file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherKind {
+    /// QMCv1, static key, unless the file has a QMCv2 footer, see [`Format::cipher_for`].
+    V1,
+    /// QMCv2, keyed by an ekey from the footer or the key database.
+    V2,
+}
+
+/// An encrypted file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    /// Extension of the encrypted file, without the leading dot.
+    pub extension: &'static str,
+
+    /// Cipher the file is encrypted with.
+    pub cipher: CipherKind,
+
+    /// Extension of the decrypted audio, without the leading dot.
+    pub audio_extension: &'static str,
+}
+
+const fn format(
+    extension: &'static str,
+    cipher: CipherKind,
+    audio_extension: &'static str,
+) -> Format {
+    Format {
+        extension,
+        cipher,
+        audio_extension,
+    }
+}
+
+// noinspection SpellCheckingInspection
+pub const FORMATS: &[Format] = &[
+    format("qmc0", CipherKind::V1, "mp3"),
+    format("qmc2", CipherKind::V1, "ogg"),
+    format("qmc3", CipherKind::V1, "mp3"),
+    format("qmcflac", CipherKind::V1, "flac"),
+    format("qmcogg", CipherKind::V1, "ogg"),
+    format("tkm", CipherKind::V1, "m4a"),
+    format("bkcmp3", CipherKind::V1, "mp3"),
+    format("bkcflac", CipherKind::V1, "flac"),
+    format("mflac", CipherKind::V2, "flac"),
+    format("mflac0", CipherKind::V2, "flac"),
+    format("mflac2", CipherKind::V2, "flac"),
+    format("mgg", CipherKind::V2, "ogg"),
+    format("mgg1", CipherKind::V2, "ogg"),
+    format("mggl", CipherKind::V2, "ogg"),
+    format("mmp4", CipherKind::V2, "m4a"),
+];
+
+/// Look up an extension (without the leading dot), ignoring case.
+pub fn from_extension(extension: &str) -> Option<&'static Format> {
+    FORMATS
+        .iter()
+        .find(|format| format.extension.eq_ignore_ascii_case(extension))
+}
+
+/// Find the encrypted extension of a file name.
+///
+/// The extension may be followed by the audio one (e.g. `song.mflac2.flac`), so every
+/// dot-separated suffix is checked from the right.
+pub fn from_filename(filename: &str) -> Option<&'static Format> {
+    let (_, extensions) = filename.split_once('.')?;
+    extensions.rsplit('.').find_map(from_extension)
+}
+
+impl Format {
+    /// Cipher of a file of this format with the given footer.
+    ///
+    /// Files with a QMCv1 extension may carry QMCv2 data as well: those are told apart by their
+    /// footer, and only the ones without a footer use the static key.
+    pub fn cipher_for(&self, footer: Option<&Metadata>) -> CipherKind {
+        match footer {
+            Some(_) => CipherKind::V2,
+            None => self.cipher,
+        }
+    }
+
+    /// Name of the decrypted file: the encrypted extension is removed, then `audio_extension`
+    /// either replaces the audio extension left behind (e.g. `song.flac.mflac2`) or is appended.
+    pub fn output_filename(&self, filename: &str, audio_extension: &str) -> String {
+        let mut parts = filename.split('.').collect::<Vec<_>>();
+        let position = parts
+            .iter()
+            .skip(1)
+            .rposition(|part| part.eq_ignore_ascii_case(self.extension))
+            .map(|i| i + 1);
+        if let Some(i) = position {
+            parts.remove(i);
+        }
+        if parts.len() > 1 && parts.last().is_some_and(|ext| is_audio_extension(ext)) {
+            parts.pop();
+        }
+        parts.push(audio_extension);
+        parts.join(".")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_filename() {
+        let format = from_filename("song.MFLAC").expect("should detect mflac");
+        assert_eq!(format.cipher, CipherKind::V2);
+        assert_eq!(format.audio_extension, "flac");
+        assert_eq!(from_filename("song.qmc0").unwrap().cipher, CipherKind::V1);
+        assert_eq!(
+            from_filename("song.mflac2.flac").unwrap().extension,
+            "mflac2"
+        );
+        assert_eq!(from_filename("song.flac"), None);
+        assert_eq!(from_filename("mflac"), None);
+    }
+
+    #[test]
+    fn test_cipher_for() {
+        let footer =
+            crate::footer::from_byte_slice(include_bytes!("footer/fixtures/ekey_android_qtag.bin"))
+                .unwrap();
+        let qmc0 = from_extension("qmc0").unwrap();
+        assert_eq!(qmc0.cipher_for(None), CipherKind::V1);
+        assert_eq!(qmc0.cipher_for(footer.as_ref()), CipherKind::V2);
+        let mflac = from_extension("mflac").unwrap();
+        assert_eq!(mflac.cipher_for(None), CipherKind::V2);
+        assert_eq!(from_extension("mmp4").unwrap().audio_extension, "m4a");
+    }
+
+    #[test]
+    fn test_output_filename() {
+        let mflac2 = from_extension("mflac2").unwrap();
+        assert_eq!(mflac2.output_filename("song.mflac2", "flac"), "song.flac");
+        assert_eq!(
+            mflac2.output_filename("song.flac.mflac2", "flac"),
+            "song.flac"
+        );
+        assert_eq!(
+            mflac2.output_filename("song.mflac2.flac", "flac"),
+            "song.flac"
+        );
+        assert_eq!(
+            mflac2.output_filename("song.flac.mflac2", "ogg"),
+            "song.ogg"
+        );
+        let tkm = from_extension("tkm").unwrap();
+        assert_eq!(tkm.output_filename("a.b.tkm", "m4a"), "a.b.m4a");
+    }
+}
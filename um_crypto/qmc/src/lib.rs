@@ -16,16 +16,23 @@ use crate::v2_rc4::cipher::QMC2RC4;
 use anyhow::Result;
 use thiserror::Error;
 
+pub mod audio;
 pub mod ekey;
 pub mod footer;
+pub mod format;
+pub mod reader;
 pub mod v1;
 pub mod v2_map;
 pub mod v2_rc4;
 
+pub use reader::QmcReader;
+
 #[derive(Error, Debug)]
 pub enum QmcCryptoError {
     #[error("QMC V2/Map Cipher: Key is empty")]
     QMCV2MapKeyEmpty,
+    #[error("QMC V2: No ekey in the footer nor provided")]
+    QMCV2EKeyMissing,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -48,6 +55,14 @@ impl QMCv2Cipher {
         Ok(cipher)
     }
 
+    /// Short name of the variant.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            QMCv2Cipher::MapL(_) => "Map",
+            QMCv2Cipher::RC4(_) => "RC4",
+        }
+    }
+
     pub fn new_from_ekey<T: AsRef<[u8]>>(ekey_str: T) -> Result<Self> {
         let key = ekey::decrypt(ekey_str)?;
         Self::new(key)
@@ -62,6 +77,45 @@ impl QMCv2Cipher {
             QMCv2Cipher::RC4(cipher) => cipher.decrypt(data, offset),
         }
     }
+
+    pub fn encrypt<T>(&self, data: &mut T, offset: usize)
+    where
+        T: AsMut<[u8]> + ?Sized,
+    {
+        match self {
+            QMCv2Cipher::MapL(cipher) => cipher.encrypt(data, offset),
+            QMCv2Cipher::RC4(cipher) => cipher.encrypt(data, offset),
+        }
+    }
+}
+
+/// Cipher of any supported file, see [`format::CipherKind`].
+#[derive(Debug, PartialEq, Clone)]
+pub enum QMCCipher {
+    V1,
+    V2(QMCv2Cipher),
+}
+
+impl QMCCipher {
+    pub fn decrypt<T>(&self, data: &mut T, offset: usize)
+    where
+        T: AsMut<[u8]> + ?Sized,
+    {
+        match self {
+            QMCCipher::V1 => v1::decrypt(data.as_mut(), offset),
+            QMCCipher::V2(cipher) => cipher.decrypt(data, offset),
+        }
+    }
+
+    pub fn encrypt<T>(&self, data: &mut T, offset: usize)
+    where
+        T: AsMut<[u8]> + ?Sized,
+    {
+        match self {
+            QMCCipher::V1 => v1::encrypt(data.as_mut(), offset),
+            QMCCipher::V2(cipher) => cipher.encrypt(data, offset),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -76,4 +130,65 @@ mod test {
             .try_into()
             .expect("failed to make test key")
     }
+
+    /// Deterministic pseudo-random bytes (xorshift), for property-style tests.
+    pub fn generate_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::generate_bytes;
+
+    #[test]
+    fn test_round_trip() {
+        // Key lengths around every boundary `QMCv2Cipher::new` chooses between.
+        for (seed, key_len) in [1usize, 2, 128, 299, 300, 301, 302, 512, 1024, 4096]
+            .into_iter()
+            .enumerate()
+        {
+            let key = generate_bytes(key_len, seed as u64);
+            let cipher = QMCv2Cipher::new(&key).expect("should create cipher");
+            match key_len {
+                1..=300 => assert!(matches!(cipher, QMCv2Cipher::MapL(_))),
+                _ => assert!(matches!(cipher, QMCv2Cipher::RC4(_))),
+            }
+
+            let plaintext = generate_bytes(0x6000, seed as u64 + 100);
+            for offset in [0, 1, 0x7F, 0x80, 0x13FF, 0x1400, 0x7FFF, 0x8000, 0x12345] {
+                let mut data = plaintext.clone();
+                cipher.encrypt(&mut data, offset);
+                assert_ne!(data, plaintext);
+                // decrypting in arbitrary chunks must undo it
+                for (i, chunk) in data.chunks_mut(0x1001).enumerate() {
+                    cipher.decrypt(chunk, offset + i * 0x1001);
+                }
+                assert_eq!(data, plaintext, "key_len={} offset={}", key_len, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip_v1() {
+        let plaintext = generate_bytes(0x9000, 42);
+        let mut data = plaintext.clone();
+        QMCCipher::V1.encrypt(&mut data, 0x10);
+        QMCCipher::V1.decrypt(&mut data, 0x10);
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn test_empty_key() {
+        assert!(QMCv2Cipher::new([]).is_err());
+    }
 }
@@ -0,0 +1,201 @@
+/*!
+ * Copyright (c) 2025 Samarium150
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::footer::Metadata;
+use crate::{footer, QMCCipher, QMCv2Cipher, QmcCryptoError};
+use anyhow::Result;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+
+/// Decrypting adapter over an encrypted file.
+///
+/// Only the audio payload is exposed: the footer is trimmed off, and reads at any position are
+/// decrypted on the fly.
+#[derive(Debug)]
+pub struct QmcReader<R> {
+    inner: R,
+    cipher: QMCCipher,
+    metadata: Option<Metadata>,
+    len: u64,
+    position: u64,
+}
+
+impl<R: Read + Seek> QmcReader<R> {
+    /// Wrap `inner`, whose first `len` bytes are encrypted with `cipher`.
+    pub fn new(mut inner: R, cipher: QMCCipher, len: u64) -> Result<Self> {
+        inner.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            inner,
+            cipher,
+            metadata: None,
+            len,
+            position: 0,
+        })
+    }
+
+    /// Wrap a QMCv2 file, locating its footer with [`footer::from_byte_slice`].
+    ///
+    /// The ekey embedded in the footer is preferred, `ekey` is used when there is none.
+    pub fn with_footer(mut inner: R, ekey: Option<&str>) -> Result<Self> {
+        let size = inner.seek(SeekFrom::End(0))?;
+        let len = size.min(footer::INITIAL_DETECTION_LEN as u64);
+        let mut buffer = vec![0u8; len as usize];
+        inner.seek(SeekFrom::Start(size - len))?;
+        inner.read_exact(&mut buffer)?;
+
+        let metadata = footer::from_byte_slice(&buffer)?;
+        let footer_size = metadata.as_ref().map_or(0, |metadata| metadata.size as u64);
+        let ekey = match metadata
+            .as_ref()
+            .and_then(|metadata| metadata.ekey.as_deref())
+        {
+            Some(ekey) => ekey,
+            None => ekey.ok_or(QmcCryptoError::QMCV2EKeyMissing)?,
+        };
+        let cipher = QMCCipher::V2(QMCv2Cipher::new_from_ekey(ekey)?);
+
+        let mut reader = Self::new(inner, cipher, size.saturating_sub(footer_size))?;
+        reader.metadata = metadata;
+        Ok(reader)
+    }
+
+    /// Footer found by [`QmcReader::with_footer`].
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    pub fn cipher(&self) -> &QMCCipher {
+        &self.cipher
+    }
+
+    /// Length of the audio payload.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for QmcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.position);
+        let n = (buf.len() as u64).min(remaining) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let n = self.inner.read(&mut buf[..n])?;
+        self.cipher.decrypt(&mut buf[..n], self.position as usize);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for QmcReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid seek position"))?;
+
+        self.inner.seek(SeekFrom::Start(position))?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ekey::{self, EKeyVersion};
+    use crate::test::generate_key;
+    use std::io::Cursor;
+
+    fn make_file(cipher: &QMCCipher, plaintext: &[u8], footer: &[u8]) -> Vec<u8> {
+        let mut data = plaintext.to_vec();
+        cipher.encrypt(&mut data, 0);
+        data.extend_from_slice(footer);
+        data
+    }
+
+    #[test]
+    fn test_read_and_seek() {
+        let plaintext = (0..0x5000u32).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        for key_len in [128, 512] {
+            let cipher = QMCCipher::V2(QMCv2Cipher::new(generate_key(key_len)).unwrap());
+            let footer = b"\x00\x00\x00\x00STag";
+            let data = make_file(&cipher, &plaintext, footer);
+
+            let mut reader = QmcReader::new(Cursor::new(data), cipher, plaintext.len() as u64)
+                .expect("should wrap");
+            let mut actual = Vec::new();
+            reader.read_to_end(&mut actual).unwrap();
+            assert_eq!(actual, plaintext);
+
+            for offset in [0x7F, 0x80, 0x1400, 0x3333] {
+                let mut actual = [0u8; 0x100];
+                reader.seek(SeekFrom::Start(offset)).unwrap();
+                reader.read_exact(&mut actual).unwrap();
+                let offset = offset as usize;
+                assert_eq!(actual, plaintext[offset..offset + 0x100]);
+            }
+
+            reader.seek(SeekFrom::End(-4)).unwrap();
+            let mut actual = Vec::new();
+            reader.read_to_end(&mut actual).unwrap();
+            assert_eq!(actual, plaintext[plaintext.len() - 4..]);
+            assert!(reader.seek(SeekFrom::Current(-0x10000)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_with_footer() {
+        let plaintext = b"fLaC\x00\x00\x00\x22 some audio frames";
+        let key = generate_key(400);
+        let ekey = ekey::encrypt(&key, EKeyVersion::V2).unwrap();
+        let payload = format!("{},326454301,2", ekey);
+        let footer = [
+            payload.as_bytes(),
+            &(payload.len() as u32).to_be_bytes(),
+            b"QTag",
+        ]
+        .concat();
+        let cipher = QMCCipher::V2(QMCv2Cipher::new(&key).unwrap());
+        let data = make_file(&cipher, plaintext, &footer);
+
+        let mut reader = QmcReader::with_footer(Cursor::new(data), None).expect("should wrap");
+        assert_eq!(reader.len(), plaintext.len() as u64);
+        assert_eq!(reader.metadata().unwrap().size, footer.len());
+        let mut actual = Vec::new();
+        reader.read_to_end(&mut actual).unwrap();
+        assert_eq!(actual, plaintext);
+    }
+
+    #[test]
+    fn test_with_footer_requires_ekey() {
+        let footer = b"0,2,001y7CaR29k6YP\x00\x00\x00\x12STag";
+        let data = [&[0u8; 0x10][..], footer].concat();
+        let err = QmcReader::with_footer(Cursor::new(data), None).expect_err("should fail");
+        assert!(matches!(
+            err.downcast_ref::<QmcCryptoError>(),
+            Some(QmcCryptoError::QMCV2EKeyMissing)
+        ));
+    }
+}
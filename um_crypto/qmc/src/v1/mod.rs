@@ -31,9 +31,21 @@ pub fn decrypt(data: &mut [u8], offset: usize) {
     }
 }
 
+/// The transform is a XOR with the key stream, so encryption is the same operation.
+pub fn encrypt(data: &mut [u8], offset: usize) {
+    decrypt(data, offset)
+}
+
 #[test]
 fn test_decryption() {
     let mut data = *b"\xab\x2f\xba\xa6\xff\x47\x80\x3d\xaa\xcd\x02";
     decrypt(&mut data, 0);
     assert_eq!(data, *b"hello world");
 }
+
+#[test]
+fn test_encryption() {
+    let mut data = *b"hello world";
+    encrypt(&mut data, 0);
+    assert_eq!(data, *b"\xab\x2f\xba\xa6\xff\x47\x80\x3d\xaa\xcd\x02");
+}
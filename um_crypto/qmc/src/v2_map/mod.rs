@@ -36,6 +36,13 @@ impl QMC2Map {
             *datum = qmc1_transform(&self.key, *datum, offset + i);
         }
     }
+
+    pub fn encrypt<T>(&self, data: &mut T, offset: usize)
+    where
+        T: AsMut<[u8]> + ?Sized,
+    {
+        self.decrypt(data, offset)
+    }
 }
 
 #[test]
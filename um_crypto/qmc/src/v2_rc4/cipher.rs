@@ -100,6 +100,13 @@ impl QMC2RC4 {
             offset += n;
         }
     }
+
+    pub fn encrypt<T>(&self, data: &mut T, offset: usize)
+    where
+        T: AsMut<[u8]> + ?Sized,
+    {
+        self.decrypt(data, offset)
+    }
 }
 
 #[cfg(test)]